@@ -0,0 +1,143 @@
+use crate::{Codec, EncodeOptions, Tuning};
+use brotli::enc::BrotliEncoderInitParams;
+use brotli::{CompressorWriter, Decompressor};
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+const BROTLI_DEFAULT_LGWIN: u32 = 22;
+pub(crate) const BROTLI_LARGE_LGWIN: u32 = 30;
+
+pub(crate) fn lgwin(tuning: &Tuning) -> u32 {
+    match tuning.brotli_lgwin {
+        Some(lgwin) => lgwin,
+        None if tuning.brotli_large_window => BROTLI_LARGE_LGWIN,
+        None => BROTLI_DEFAULT_LGWIN,
+    }
+}
+
+/// Passes writes through, keeping the first error. The brotli writer drops
+/// any error from finishing the stream, so it is checked here instead.
+struct Checked<'a> {
+    inner: &'a mut dyn Write,
+    error: Option<io::Error>,
+}
+
+impl Write for Checked<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.error.get_or_insert_with(|| io::ErrorKind::WriteZero.into());
+                Ok(0)
+            }
+            Err(e) if e.kind() != io::ErrorKind::Interrupted => {
+                let kind = e.kind();
+                self.error.get_or_insert(e);
+                Err(kind.into())
+            }
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct Brotli;
+
+impl Codec for Brotli {
+    fn name(&self) -> &'static str {
+        "brotli"
+    }
+
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        0..=11
+    }
+
+    fn level_hint(&self) -> &'static str {
+        "Level 0 = fastest/lowest compression\n\
+         Level 11 = slowest/highest compression (best ratio)"
+    }
+
+    fn sweep(&self) -> &'static [u32] {
+        &[1, 5, 9, 11]
+    }
+
+    fn describe(&self, tuning: &Tuning) -> Vec<(&'static str, String)> {
+        let mut params = vec![("brotli_lgwin", lgwin(tuning).to_string())];
+        if tuning.brotli_large_window {
+            params.push(("brotli_large_window", "true".to_string()));
+        }
+        params
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut params = BrotliEncoderInitParams();
+        params.quality = options.level as i32;
+        params.lgwin = lgwin(options.tuning) as i32;
+        params.large_window = options.tuning.brotli_large_window;
+        let mut output = Checked { inner: output, error: None };
+        let mut encoder = CompressorWriter::with_params(&mut output, 4096, &params);
+        let copied = io::copy(input, &mut encoder);
+        // Taking the writer back finishes the brotli stream
+        encoder.into_inner();
+        match output.error {
+            Some(e) => Err(e),
+            None => copied.map(|_| ()),
+        }
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut Decompressor::new(input, 4096), output)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails every write, like a full disk.
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn options(tuning: &Tuning) -> EncodeOptions<'_> {
+        EncodeOptions {
+            level: 9,
+            tuning,
+            threads: 1,
+            x86_filter: false,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let tuning = Tuning::default();
+        let data: Vec<u8> = (0..100_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let compressed = crate::encode(&Brotli, &options(&tuning), &data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(crate::decode(&Brotli, &compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn finish_error_is_returned() {
+        // Small enough that nothing is written before the stream is finished
+        let tuning = Tuning::default();
+        let error = Brotli.encode(&options(&tuning), &mut &b"hello brotli"[..], &mut Full).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(error.to_string(), "disk full");
+    }
+}
@@ -0,0 +1,46 @@
+use crate::{Codec, EncodeOptions};
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+pub struct Bzip2;
+
+impl Codec for Bzip2 {
+    fn name(&self) -> &'static str {
+        "bzip2"
+    }
+
+    fn id(&self) -> u8 {
+        5
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        1..=9
+    }
+
+    fn level_hint(&self) -> &'static str {
+        "The level selects the block size, 100 KB to 900 KB"
+    }
+
+    fn sweep(&self) -> &'static [u32] {
+        &[1, 9]
+    }
+
+    fn magic(&self) -> Option<&'static [u8]> {
+        Some(b"BZh")
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = BzEncoder::new(output, Compression::new(options.level));
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut MultiBzDecoder::new(input), output)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{Codec, EncodeOptions};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+// Raw deflate has no stream header, so entries rely on the recorded codec ID
+pub struct Deflate;
+
+impl Codec for Deflate {
+    fn name(&self) -> &'static str {
+        "deflate"
+    }
+
+    fn id(&self) -> u8 {
+        6
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        0..=9
+    }
+
+    fn level_hint(&self) -> &'static str {
+        "Level 0 = stored without compression\n\
+         Level 9 = slowest/highest compression (best ratio)"
+    }
+
+    fn sweep(&self) -> &'static [u32] {
+        &[1, 6, 9]
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(output, Compression::new(options.level));
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut DeflateDecoder::new(input), output)?;
+        Ok(())
+    }
+}
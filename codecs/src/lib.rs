@@ -0,0 +1,149 @@
+// Codecs shared by the packer and the stub loader. Each codec is one
+// implementation of `Codec`; adding a codec means writing the implementation
+// and listing it in `registry`.
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+mod brotli;
+#[cfg(feature = "bzip2")]
+mod bzip2;
+#[cfg(feature = "deflate")]
+mod deflate;
+mod lz4;
+mod lzma;
+mod zstd;
+
+use crate::brotli::BROTLI_LARGE_LGWIN;
+pub use crate::zstd::ZSTD_WINDOW_LOG_MAX;
+
+/// Codec parameters beyond the compression level. Each field only applies to
+/// its own codec and is ignored by the others.
+#[derive(Debug, Clone, Default)]
+pub struct Tuning {
+    pub xz_extreme: bool,
+    pub xz_dict_size: Option<u32>,
+    pub zstd_window_log: Option<u32>,
+    pub brotli_lgwin: Option<u32>,
+    pub brotli_large_window: bool,
+}
+
+impl Tuning {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(size) = self.xz_dict_size {
+            if !(4096..=1536 * 1024 * 1024).contains(&size) {
+                return Err(format!("Invalid xz dictionary size {}. Supported range is 4 KiB to 1536 MiB.", size));
+            }
+        }
+        if let Some(window_log) = self.zstd_window_log {
+            if !(10..=ZSTD_WINDOW_LOG_MAX).contains(&window_log) {
+                return Err(format!("Invalid zstd window log {}. Supported window logs are 10-{}.", window_log, ZSTD_WINDOW_LOG_MAX));
+            }
+        }
+        let max_lgwin = if self.brotli_large_window { BROTLI_LARGE_LGWIN } else { 24 };
+        if !(10..=max_lgwin).contains(&brotli::lgwin(self)) {
+            return Err(format!(
+                "Invalid brotli window {}. Brotli supports 10-24, or up to 30 with --brotli-large-window.",
+                brotli::lgwin(self)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Settings for one encode call.
+pub struct EncodeOptions<'a> {
+    pub level: u32,
+    pub tuning: &'a Tuning,
+    /// Worker threads; codecs without a multithreaded encoder ignore it
+    pub threads: u32,
+    /// Run the x86 branch filter inside the codec; only honoured when
+    /// `Codec::chains_x86` is true
+    pub x86_filter: bool,
+}
+
+pub trait Codec: Send + Sync {
+    /// Name used on the command line and in metadata
+    fn name(&self) -> &'static str;
+
+    /// Identifier recorded in each entry header (`codec=<id>`)
+    fn id(&self) -> u8;
+
+    fn levels(&self) -> RangeInclusive<u32>;
+
+    /// Extra guidance printed when a level is out of range
+    fn level_hint(&self) -> &'static str {
+        ""
+    }
+
+    /// Levels tried by `--algo auto` and `bench`
+    fn sweep(&self) -> &'static [u32];
+
+    /// Stream header identifying this codec in containers written before
+    /// entries carried a codec ID
+    fn magic(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    /// Whether `EncodeOptions::threads` can speed up a single stream
+    fn multithreaded(&self) -> bool {
+        false
+    }
+
+    /// Whether the x86 branch filter can run as part of the codec itself
+    fn chains_x86(&self) -> bool {
+        false
+    }
+
+    /// Tuning parameters in effect, as (name, value) pairs for reports and metadata
+    fn describe(&self, _tuning: &Tuning) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Every codec compiled into this build, in the order they are listed to users.
+pub fn registry() -> &'static [&'static dyn Codec] {
+    &[
+        &lzma::Lzma,
+        &zstd::Zstd,
+        &brotli::Brotli,
+        &lz4::Lz4,
+        #[cfg(feature = "bzip2")]
+        &bzip2::Bzip2,
+        #[cfg(feature = "deflate")]
+        &deflate::Deflate,
+    ]
+}
+
+pub fn by_name(name: &str) -> Option<&'static dyn Codec> {
+    registry().iter().copied().find(|c| c.name() == name)
+}
+
+pub fn by_id(id: u8) -> Option<&'static dyn Codec> {
+    registry().iter().copied().find(|c| c.id() == id)
+}
+
+/// Codec of a payload without a recorded ID, from its stream header.
+/// Brotli has no magic bytes, so it is assumed for anything else.
+pub fn detect(data: &[u8]) -> &'static dyn Codec {
+    registry()
+        .iter()
+        .copied()
+        .find(|c| c.magic().is_some_and(|m| data.starts_with(m)))
+        .unwrap_or(&brotli::Brotli)
+}
+
+pub fn encode(codec: &dyn Codec, options: &EncodeOptions, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    codec.encode(options, &mut &data[..], &mut out)?;
+    Ok(out)
+}
+
+pub fn decode(codec: &dyn Codec, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    codec.decode(&mut &data[..], &mut out)?;
+    Ok(out)
+}
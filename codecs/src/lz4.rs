@@ -0,0 +1,46 @@
+use crate::{Codec, EncodeOptions};
+use lz4::{Decoder, EncoderBuilder};
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+pub struct Lz4;
+
+impl Codec for Lz4 {
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn id(&self) -> u8 {
+        4
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        0..=12
+    }
+
+    fn level_hint(&self) -> &'static str {
+        "Level 0-2 = fast LZ4 (fastest compression)\n\
+         Level 3-12 = LZ4 HC (slower compression, same fast decompression)"
+    }
+
+    fn sweep(&self) -> &'static [u32] {
+        &[1, 9, 12]
+    }
+
+    fn magic(&self) -> Option<&'static [u8]> {
+        Some(&[0x04, 0x22, 0x4D, 0x18])
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        // Levels 3 and up select the LZ4 HC compressor; the frame format is the same
+        let mut encoder = EncoderBuilder::new().level(options.level).build(output)?;
+        io::copy(input, &mut encoder)?;
+        let (_, result) = encoder.finish();
+        result
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut Decoder::new(input)?, output)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{Codec, EncodeOptions, Tuning};
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, Filters, LzmaOptions, MtStreamBuilder, Stream};
+use xz2::write::XzEncoder;
+
+const LZMA_PRESET_EXTREME: u32 = 0x8000_0000;
+
+pub struct Lzma;
+
+impl Codec for Lzma {
+    fn name(&self) -> &'static str {
+        "lzma"
+    }
+
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        0..=9
+    }
+
+    fn level_hint(&self) -> &'static str {
+        "Level 0 = fastest/lowest compression\n\
+         Level 9 = slowest/highest compression (best ratio)\n\
+         Recommended: 3-6 for balanced performance"
+    }
+
+    fn sweep(&self) -> &'static [u32] {
+        &[1, 3, 6, 9]
+    }
+
+    fn magic(&self) -> Option<&'static [u8]> {
+        Some(&[0xFD, b'7', b'z', b'X', b'Z', 0x00])
+    }
+
+    fn multithreaded(&self) -> bool {
+        true
+    }
+
+    fn chains_x86(&self) -> bool {
+        true
+    }
+
+    fn describe(&self, tuning: &Tuning) -> Vec<(&'static str, String)> {
+        let mut params = vec![];
+        if tuning.xz_extreme {
+            params.push(("xz_extreme", "true".to_string()));
+        }
+        if let Some(size) = tuning.xz_dict_size {
+            params.push(("xz_dict_size", size.to_string()));
+        }
+        params
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let tuning = options.tuning;
+        let preset = if tuning.xz_extreme { options.level | LZMA_PRESET_EXTREME } else { options.level };
+        let mut lzma_options = LzmaOptions::new_preset(preset)?;
+        if let Some(size) = tuning.xz_dict_size {
+            lzma_options.dict_size(size);
+        }
+        let mut filters = Filters::new();
+        if options.x86_filter {
+            filters.x86();
+        }
+        filters.lzma2(&lzma_options);
+        // The multithreaded encoder splits the input into independent blocks
+        // (3x the dictionary size by default), so only use it when asked to
+        let stream = if options.threads > 1 {
+            MtStreamBuilder::new()
+                .threads(options.threads)
+                .filters(filters)
+                .check(Check::Crc64)
+                .encoder()?
+        } else {
+            Stream::new_stream_encoder(&filters, Check::Crc64)?
+        };
+        let mut encoder = XzEncoder::new_stream(output, stream);
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        io::copy(&mut XzDecoder::new(input), output)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{Codec, EncodeOptions, Tuning};
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use zstd::stream::{Decoder, Encoder};
+
+/// Largest window log zstd accepts on every platform (1 GiB); 31 is only
+/// valid on 64-bit targets, and the stub must decode what the packer writes
+pub const ZSTD_WINDOW_LOG_MAX: u32 = 30;
+
+pub struct Zstd;
+
+impl Codec for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        1..=22
+    }
+
+    fn level_hint(&self) -> &'static str {
+        "Level 1 = fastest/lowest compression\n\
+         Level 22 = slowest/highest compression (best ratio)\n\
+         Recommended: 3-19 for balanced performance"
+    }
+
+    fn sweep(&self) -> &'static [u32] {
+        &[1, 3, 9, 15, 19]
+    }
+
+    fn magic(&self) -> Option<&'static [u8]> {
+        Some(&[0x28, 0xB5, 0x2F, 0xFD])
+    }
+
+    fn multithreaded(&self) -> bool {
+        cfg!(feature = "zstdmt")
+    }
+
+    fn describe(&self, tuning: &Tuning) -> Vec<(&'static str, String)> {
+        match tuning.zstd_window_log {
+            Some(window_log) => vec![
+                ("zstd_long", "true".to_string()),
+                ("zstd_window_log", window_log.to_string()),
+            ],
+            None => vec![],
+        }
+    }
+
+    fn encode(&self, options: &EncodeOptions, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut encoder = Encoder::new(output, options.level as i32)?;
+        #[cfg(feature = "zstdmt")]
+        if options.threads > 1 {
+            encoder.multithread(options.threads)?;
+        }
+        if let Some(window_log) = options.tuning.zstd_window_log {
+            encoder.long_distance_matching(true)?;
+            encoder.window_log(window_log)?;
+        }
+        io::copy(input, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut decoder = Decoder::new(input)?;
+        decoder.window_log_max(ZSTD_WINDOW_LOG_MAX)?;
+        io::copy(&mut decoder, output)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+// Zip and tar archives added with --add-archive are read in place: their
+// members are listed up front, then streamed into the packer when it
+// compresses them, so nothing is unpacked to disk.
+use crate::report;
+use flate2::read::MultiGzDecoder;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tar::EntryType;
+use zip::ZipArchive;
+
+// File type bits of a Unix mode, as stored by zip tools that record them
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+/// The mapped archive, shared by the threads reading its members
+#[derive(Clone)]
+struct Mapped(Arc<Mmap>);
+
+impl AsRef<[u8]> for Mapped {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+enum Format {
+    /// Members are read by index from the central directory
+    Zip(ZipArchive<Cursor<Mapped>>),
+    /// Members can only be read in order
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+pub struct Archive {
+    pub path: PathBuf,
+    map: Mapped,
+    format: Format,
+}
+
+#[derive(Clone)]
+pub enum Kind {
+    File,
+    Symlink(String),
+}
+
+/// A file or symlink in an archive.
+#[derive(Clone)]
+pub struct Member {
+    /// Position of the data in the archive, used to read the member later;
+    /// for a hard link, the position of the file it links to
+    pub index: usize,
+    /// Path in the archive, with `/` separators
+    pub path: String,
+    pub kind: Kind,
+    pub size: u64,
+    /// Unix permission bits, if the archive records them
+    pub mode: Option<u32>,
+}
+
+/// A member path made relative with `/` separators, or `None` if it would
+/// end up outside the extraction directory.
+fn member_path(path: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let mut parts = vec![];
+    for component in Path::new(&path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+impl Archive {
+    /// Opens a zip, tar, tar.gz or tar.zst archive, recognised by its content.
+    pub fn open(path: &Path) -> io::Result<Archive> {
+        if !path.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such archive file", path.display())));
+        }
+        let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        // SAFETY: the archive is only read, and is not expected to change while packing
+        let map = Mapped(Arc::new(unsafe { Mmap::map(&file)? }));
+        let data = map.as_ref();
+        let format = if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            let zip = ZipArchive::new(Cursor::new(map.clone()));
+            Format::Zip(zip.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?)
+        } else if data.starts_with(&[0x1f, 0x8b]) {
+            Format::TarGz
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Format::TarZst
+        } else if data.get(257..262) == Some(b"ustar") {
+            Format::Tar
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: not a zip, tar, tar.gz or tar.zst archive", path.display()),
+            ));
+        };
+        Ok(Archive {
+            path: path.to_path_buf(),
+            map,
+            format,
+        })
+    }
+
+    fn invalid(&self, message: impl Display) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", self.path.display(), message))
+    }
+
+    /// Whether members can only be read in archive order, so reading many of
+    /// them should go through `for_each`.
+    pub fn is_sequential(&self) -> bool {
+        !matches!(self.format, Format::Zip(_))
+    }
+
+    fn tar(&self) -> io::Result<tar::Archive<Box<dyn Read + '_>>> {
+        let data: &[u8] = self.map.as_ref();
+        let reader: Box<dyn Read> = match self.format {
+            Format::TarGz => Box::new(MultiGzDecoder::new(data)),
+            Format::TarZst => Box::new(zstd::stream::read::Decoder::new(data)?),
+            _ => Box::new(data),
+        };
+        Ok(tar::Archive::new(reader))
+    }
+
+    /// Lists the files and symlinks in the archive. Directories are left
+    /// out, as extracting the files creates them. A hard link becomes a
+    /// copy of the file it links to.
+    pub fn members(&self) -> io::Result<Vec<Member>> {
+        let mut members = vec![];
+        match &self.format {
+            Format::Zip(zip) => {
+                let mut zip = zip.clone();
+                for index in 0..zip.len() {
+                    let mut file = zip.by_index(index).map_err(|e| self.invalid(e))?;
+                    if file.is_dir() {
+                        continue;
+                    }
+                    let path = member_path(file.name())
+                        .ok_or_else(|| self.invalid(format!("member path '{}' leaves the archive", file.name())))?;
+                    let mode = file.unix_mode();
+                    let (kind, size) = if mode.is_some_and(|m| m & S_IFMT == S_IFLNK) {
+                        let mut target = String::new();
+                        file.read_to_string(&mut target)?;
+                        (Kind::Symlink(target), 0)
+                    } else {
+                        (Kind::File, file.size())
+                    };
+                    members.push(Member {
+                        index,
+                        path,
+                        kind,
+                        size,
+                        mode: mode.map(|m| m & 0o7777),
+                    });
+                }
+            }
+            _ => {
+                // Hard links refer to a file earlier in the archive by its path
+                let mut by_path: HashMap<String, Member> = HashMap::new();
+                for (index, entry) in self.tar()?.entries()?.enumerate() {
+                    let entry = entry.map_err(|e| self.invalid(e))?;
+                    let raw_path = entry.path()?.to_string_lossy().to_string();
+                    let path = member_path(&raw_path)
+                        .ok_or_else(|| self.invalid(format!("member path '{}' leaves the archive", raw_path)))?;
+                    let header = entry.header();
+                    let link_name = entry.link_name()?.map(|target| target.to_string_lossy().to_string());
+                    let member = match header.entry_type() {
+                        EntryType::Regular | EntryType::Continuous => Member {
+                            index,
+                            path,
+                            kind: Kind::File,
+                            size: header.size()?,
+                            mode: header.mode().ok().map(|m| m & 0o7777),
+                        },
+                        EntryType::Symlink => Member {
+                            index,
+                            path,
+                            kind: Kind::Symlink(link_name.unwrap_or_default()),
+                            size: 0,
+                            mode: None,
+                        },
+                        EntryType::Link => {
+                            let target = link_name.as_deref().and_then(member_path).and_then(|t| by_path.get(&t));
+                            match target {
+                                Some(target) => Member {
+                                    path,
+                                    ..target.clone()
+                                },
+                                None => {
+                                    report::warn(format!(
+                                        "skipping hard link {} in {}: its target is not in the archive",
+                                        raw_path,
+                                        self.path.display()
+                                    ));
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => continue,
+                    };
+                    by_path.insert(member.path.clone(), member.clone());
+                    members.push(member);
+                }
+            }
+        }
+        Ok(members)
+    }
+
+    /// Runs `f` on the data of the member at `index`. A tar archive is read
+    /// from the start up to the member each time.
+    pub fn read<T>(&self, index: usize, f: impl FnOnce(&mut dyn Read) -> io::Result<T>) -> io::Result<T> {
+        match &self.format {
+            Format::Zip(zip) => {
+                let mut zip = zip.clone();
+                let mut file = zip.by_index(index).map_err(|e| self.invalid(e))?;
+                f(&mut file)
+            }
+            _ => {
+                let mut tar = self.tar()?;
+                let entry = tar.entries()?.nth(index);
+                let mut entry = entry.ok_or_else(|| self.invalid(format!("no member {}", index)))??;
+                f(&mut entry)
+            }
+        }
+    }
+
+    /// Runs `f` on every member in archive order, reading the archive once.
+    pub fn for_each(&self, mut f: impl FnMut(usize, &mut dyn Read) -> io::Result<()>) -> io::Result<()> {
+        match &self.format {
+            Format::Zip(zip) => {
+                for index in 0..zip.len() {
+                    self.read(index, |input| f(index, input))?;
+                }
+            }
+            _ => {
+                for (index, entry) in self.tar()?.entries()?.enumerate() {
+                    f(index, &mut entry.map_err(|e| self.invalid(e))?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use crate::codec::compress_entry;
+use crate::error::PackError;
+use crate::extras::{self, ExtraFile};
+use crate::filter;
+use crate::report;
+use clap::ValueEnum;
+use execompress_codecs::{Codec, Tuning};
+use log::info;
+use std::io::{self, Read};
+use std::time::Instant;
+
+// Trial data is capped so a sweep over a multi-GB input stays quick
+const INPUT_SAMPLE_BYTES: usize = 8 * 1024 * 1024;
+const INPUT_SAMPLE_CHUNKS: usize = 8;
+const EXTRA_SAMPLE_FILES: usize = 32;
+const EXTRA_SAMPLE_FILE_BYTES: usize = 1024 * 1024;
+
+// Assumed disk read speed when estimating startup cost of a larger payload
+const READ_BYTES_PER_SEC: f64 = 200.0 * 1024.0 * 1024.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Objective {
+    /// Smallest output
+    Size,
+    /// Smallest output that decompresses within 2x of the fastest candidate
+    Balanced,
+    /// Lowest estimated read + decompression time at launch
+    Startup,
+}
+
+impl Objective {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Objective::Size => "size",
+            Objective::Balanced => "balanced",
+            Objective::Startup => "startup",
+        }
+    }
+}
+
+/// Wall-clock limit for compressing the whole input, used to rule out
+/// candidates whose extrapolated compression time would not fit.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub secs: f64,
+    /// --parallel: extra files are compressed this many at a time, and the
+    /// main input is split across this many threads by multithreaded codecs
+    pub threads: usize,
+}
+
+/// Result of trial-compressing the sample with one codec and level.
+#[derive(Clone)]
+pub struct Candidate {
+    pub codec: &'static dyn Codec,
+    pub level: u32,
+    pub original: usize,
+    pub compressed: usize,
+    pub compress_secs: f64,
+    pub decompress_secs: f64,
+    /// Compression time extrapolated to the full input, when packing on a budget
+    pub estimated_secs: Option<f64>,
+}
+
+impl Candidate {
+    fn startup_secs(&self) -> f64 {
+        self.decompress_secs + self.compressed as f64 / READ_BYTES_PER_SEC
+    }
+}
+
+/// Takes evenly spaced chunks of a large input so the sample covers code,
+/// data and resources alike.
+fn sample_input(data: &[u8]) -> Vec<u8> {
+    if data.len() <= INPUT_SAMPLE_BYTES {
+        return data.to_vec();
+    }
+    let chunk = INPUT_SAMPLE_BYTES / INPUT_SAMPLE_CHUNKS;
+    let stride = data.len() / INPUT_SAMPLE_CHUNKS;
+    let mut sample = Vec::with_capacity(INPUT_SAMPLE_BYTES);
+    for i in 0..INPUT_SAMPLE_CHUNKS {
+        let start = i * stride;
+        sample.extend_from_slice(&data[start..start + chunk]);
+    }
+    sample
+}
+
+/// Picks up to EXTRA_SAMPLE_FILES files spread across the extra files.
+fn sample_extras(files: &[ExtraFile]) -> io::Result<Vec<Vec<u8>>> {
+    let files: Vec<ExtraFile> = files.iter().filter(|f| f.link.is_none()).cloned().collect();
+    let step = files.len().div_ceil(EXTRA_SAMPLE_FILES).max(1);
+    let picked: Vec<ExtraFile> = files.into_iter().step_by(step).collect();
+    let samples = extras::read_all(&picked, |file, input| {
+        let mut data = vec![];
+        let read = input.take(EXTRA_SAMPLE_FILE_BYTES as u64).read_to_end(&mut data);
+        read.map_err(|e| PackError::extra(&file.source.path(), e))?;
+        Ok(data)
+    })?;
+    Ok(samples)
+}
+
+fn trial(codec: &'static dyn Codec, level: u32, tuning: &Tuning, bcj: bool, pieces: &[Vec<u8>]) -> io::Result<Candidate> {
+    let mut candidate = Candidate {
+        codec,
+        level,
+        original: 0,
+        compressed: 0,
+        compress_secs: 0.0,
+        decompress_secs: 0.0,
+        estimated_secs: None,
+    };
+
+    // Each piece is compressed on its own, the same way pack handles extra files
+    for piece in pieces {
+        let filter = if bcj { filter::detect(piece) } else { None };
+        let start = Instant::now();
+        let entry = compress_entry(String::new(), codec, level, tuning, 1, filter, piece)?;
+        candidate.compress_secs += start.elapsed().as_secs_f64();
+
+        let start = Instant::now();
+        let decompressed = entry.decompress()?;
+        candidate.decompress_secs += start.elapsed().as_secs_f64();
+
+        if decompressed.len() != piece.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} level {} failed to round-trip", codec.name(), level)));
+        }
+        candidate.original += piece.len();
+        candidate.compressed += entry.data.len();
+    }
+    Ok(candidate)
+}
+
+/// Scales the sampled throughput up to the full input and extra files.
+fn estimate_secs(candidate: &Candidate, input_bytes: usize, extra_bytes: u64, budget: &Budget) -> f64 {
+    let secs_per_byte = candidate.compress_secs / candidate.original.max(1) as f64;
+    let threads = budget.threads.max(1) as f64;
+    let main_threads = if candidate.codec.multithreaded() { threads } else { 1.0 };
+    input_bytes as f64 * secs_per_byte / main_threads + extra_bytes as f64 * secs_per_byte / threads
+}
+
+/// Picks the candidate that best fits `objective`; with `remaining_secs`, only
+/// among those expected to finish compressing in what is left of the budget.
+fn pick(candidates: &[Candidate], objective: Objective, remaining_secs: Option<f64>) -> usize {
+    // Candidates expected to finish within the budget; if none do, the
+    // quickest one is the best that can be done
+    let within: Vec<&Candidate> = match remaining_secs {
+        Some(remaining) => {
+            let fits: Vec<&Candidate> = candidates
+                .iter()
+                .filter(|c| c.estimated_secs.is_some_and(|secs| secs <= remaining))
+                .collect();
+            if fits.is_empty() {
+                let quickest = candidates
+                    .iter()
+                    .min_by(|a, b| a.estimated_secs.unwrap_or(0.0).total_cmp(&b.estimated_secs.unwrap_or(0.0)))
+                    .unwrap();
+                report::warn(format!(
+                    "no candidate is expected to finish within the {:.1} s left of the budget; using the quickest ({} level {})",
+                    remaining,
+                    quickest.codec.name(),
+                    quickest.level
+                ));
+                vec![quickest]
+            } else {
+                fits
+            }
+        }
+        None => candidates.iter().collect(),
+    };
+
+    let by_size = |a: &&Candidate, b: &&Candidate| {
+        a.compressed.cmp(&b.compressed).then(a.decompress_secs.total_cmp(&b.decompress_secs))
+    };
+    let best = match objective {
+        Objective::Size => within.iter().copied().min_by(by_size),
+        Objective::Balanced => {
+            let fastest = within.iter().map(|c| c.decompress_secs).fold(f64::INFINITY, f64::min);
+            within
+                .iter()
+                .copied()
+                .filter(|c| c.decompress_secs <= fastest * 2.0)
+                .min_by(by_size)
+        }
+        Objective::Startup => within
+            .iter()
+            .copied()
+            .min_by(|a, b| a.startup_secs().total_cmp(&b.startup_secs())),
+    };
+    let best = best.unwrap();
+    candidates.iter().position(|c| std::ptr::eq(c, best)).unwrap()
+}
+
+fn mb_per_sec(bytes: usize, secs: f64) -> f64 {
+    bytes as f64 / 1024.0 / 1024.0 / secs.max(1e-9)
+}
+
+fn extras_size(files: &[ExtraFile]) -> u64 {
+    files.iter().map(|f| f.size).sum()
+}
+
+/// Trial-compresses a sample of the input and extra files with each of
+/// `codecs` over its sweep levels, prints a comparison table and returns the
+/// candidate that best fits `objective`. With a `budget`, only candidates
+/// whose extrapolated compression time fits in what the sweep has left of
+/// it are considered. Branch filters are applied only with `bcj`, as in the
+/// pack itself.
+pub fn select(
+    input: &[u8],
+    extras: &[ExtraFile],
+    objective: Objective,
+    tuning: &Tuning,
+    bcj: bool,
+    codecs: &[&'static dyn Codec],
+    budget: Option<&Budget>,
+) -> io::Result<Candidate> {
+    let mut pieces = vec![sample_input(input)];
+    pieces.extend(sample_extras(extras)?);
+    let extra_bytes = extras_size(extras);
+    let sample_bytes: usize = pieces.iter().map(|p| p.len()).sum();
+    info!(
+        "Selecting codec for objective '{}' from a {} byte sample ({} files)...",
+        objective.name(),
+        sample_bytes,
+        pieces.len()
+    );
+
+    // The sweep itself counts against the budget
+    let started = Instant::now();
+    let remaining_secs = || budget.map(|b| (b.secs - started.elapsed().as_secs_f64()).max(0.0));
+
+    let mut candidates = vec![];
+    'sweep: for &codec in codecs {
+        for &level in codec.sweep() {
+            if !candidates.is_empty() && remaining_secs().is_some_and(|secs| secs <= 0.0) {
+                info!("Time budget spent after {} candidates; stopping the sweep", candidates.len());
+                break 'sweep;
+            }
+            let mut candidate = trial(codec, level, tuning, bcj, &pieces)?;
+            let mut over_budget = false;
+            if let (Some(budget), Some(remaining)) = (budget, remaining_secs()) {
+                let secs = estimate_secs(&candidate, input.len(), extra_bytes, budget);
+                candidate.estimated_secs = Some(secs);
+                over_budget = secs > remaining;
+            }
+            candidates.push(candidate);
+            // Higher levels are slower still, so stop escalating this codec
+            if over_budget {
+                break;
+            }
+        }
+    }
+    let chosen = pick(&candidates, objective, remaining_secs());
+
+    info!(
+        "  {:<8} {:>5} {:>12} {:>8} {:>14} {:>16} {:>10}",
+        "codec", "level", "size", "ratio", "compress MB/s", "decompress MB/s", "est. s"
+    );
+    for (i, c) in candidates.iter().enumerate() {
+        info!(
+            "{} {:<8} {:>5} {:>12} {:>7.2}% {:>14.1} {:>16.1} {:>10}",
+            if i == chosen { "*" } else { " " },
+            c.codec.name(),
+            c.level,
+            c.compressed,
+            c.compressed as f64 / c.original.max(1) as f64 * 100.0,
+            mb_per_sec(c.original, c.compress_secs),
+            mb_per_sec(c.original, c.decompress_secs),
+            c.estimated_secs.map(|s| format!("{:.1}", s)).unwrap_or_else(|| "-".to_string())
+        );
+    }
+
+    Ok(candidates.swap_remove(chosen))
+}
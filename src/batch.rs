@@ -0,0 +1,286 @@
+// `batch` packs every `[[batch]]` entry of a config file in one run. Settings
+// are resolved one entry at a time, a stub is built once per distinct set of
+// stub settings, and the packs then run in parallel.
+use crate::config::{self, Config};
+use crate::error::PackError;
+use crate::report::{self, REPORT_VERSION};
+use crate::{apply_config, prepare, run, Args, Commands, Job, Outcome};
+use clap::{CommandFactory, FromArgMatches};
+use globset::GlobBuilder;
+use log::{error, info};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+use walkdir::WalkDir;
+
+/// One pack of the batch and how it went.
+struct Item {
+    label: String,
+    result: Result<Outcome, PackError>,
+    secs: f64,
+}
+
+fn is_pattern(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Files matching the glob `pattern`, walked from the directory before its
+/// first wildcard, sorted by path.
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>, String> {
+    let text = pattern.to_string_lossy().replace('\\', "/");
+    let glob = GlobBuilder::new(&text)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("Invalid input pattern '{}': {}", text, e))?
+        .compile_matcher();
+    let base: PathBuf = pattern
+        .components()
+        .take_while(|c| !is_pattern(Path::new(c.as_os_str())))
+        .collect();
+    let rest = pattern.components().count() - base.components().count();
+    let mut walk = WalkDir::new(if base.as_os_str().is_empty() { Path::new(".") } else { &base });
+    if !text.contains("**") {
+        walk = walk.max_depth(rest);
+    }
+    let mut matches = vec![];
+    for entry in walk.into_iter().filter_map(Result::ok).filter(|e| e.file_type().is_file()) {
+        // Walking "." yields "./name", which a relative pattern does not start with
+        let path = match base.as_os_str().is_empty() {
+            true => entry.path().strip_prefix(".").unwrap_or(entry.path()).to_path_buf(),
+            false => entry.path().to_path_buf(),
+        };
+        if glob.is_match(path.to_string_lossy().replace('\\', "/")) {
+            matches.push(path);
+        }
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+/// The entries to pack, one per input: an entry whose `input` is a glob
+/// becomes one per matching file, written into its `output` directory.
+fn entries(items: Vec<Config>) -> Vec<Result<Config, (String, PackError)>> {
+    let mut entries = vec![];
+    for (i, item) in items.into_iter().enumerate() {
+        let label = |item: &Config| match (&item.output, &item.input) {
+            (Some(output), _) => output.display().to_string(),
+            (None, Some(input)) => input.display().to_string(),
+            (None, None) => format!("batch[{}]", i),
+        };
+        let (Some(input), Some(output)) = (&item.input, &item.output) else {
+            let e = PackError::Settings("needs both `input` and `output`".to_string());
+            entries.push(Err((label(&item), e)));
+            continue;
+        };
+        if !is_pattern(input) {
+            entries.push(Ok(item));
+            continue;
+        }
+        match expand(input) {
+            Ok(inputs) if inputs.is_empty() => entries.push(Err((label(&item), PackError::input(input, "no files match")))),
+            Ok(inputs) => {
+                for input in inputs {
+                    let output = output.join(input.file_name().unwrap());
+                    entries.push(Ok(Config {
+                        input: Some(input),
+                        output: Some(output),
+                        ..item.clone()
+                    }));
+                }
+            }
+            Err(e) => entries.push(Err((label(&item), e.into()))),
+        }
+    }
+    entries
+}
+
+/// Resolves one entry into a job, as `pack` would with only a config file.
+fn prepare_entry(item: &Config, target: Option<&str>) -> Result<Job, PackError> {
+    let matches = Args::command().get_matches_from(["execompress", "pack"]);
+    let Some(Commands::Pack(mut args)) = Args::from_arg_matches(&matches).map_err(|e| e.to_string())?.command else {
+        unreachable!("`pack` parses into Commands::Pack");
+    };
+    let from_config = apply_config(&mut args, matches.subcommand_matches("pack").unwrap(), item)?;
+    args.target = target.map(|t| t.to_string());
+    prepare(*args, |id| from_config.contains(&id), item).map(|job| job.unwrap())
+}
+
+/// Packs every `[[batch]]` entry of the config file at `path` (or
+/// execompress.toml), running `jobs` packs at a time, and prints a summary.
+/// If any entry failed, returns `PackError::Batch` with the exit code of
+/// the first failure.
+pub fn batch(path: Option<&Path>, target: Option<&str>, jobs: usize, report_path: Option<&Path>) -> Result<(), PackError> {
+    let start = Instant::now();
+    let config = config::load(path).map_err(|e| PackError::Settings(e.to_string()))?;
+    let items = config.batch_items(target)?;
+    if items.is_empty() {
+        let path = path.unwrap_or(Path::new(config::CONFIG_FILE));
+        return Err(PackError::Settings(format!("{}: no [[batch]] entries to pack", path.display())));
+    }
+
+    let entries = entries(items);
+    let mut failed = vec![];
+    let mut jobs_to_run = vec![];
+    let mut outputs: HashMap<PathBuf, String> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let item = match entry {
+            Ok(item) => item,
+            Err((label, e)) => {
+                failed.push(Item {
+                    label: label.clone(),
+                    result: Err(e.clone()),
+                    secs: 0.0,
+                });
+                continue;
+            }
+        };
+        let output = item.output.clone().unwrap();
+        let label = output.display().to_string();
+        info!("\n[{}/{}] {} -> {}", i + 1, entries.len(), item.input.as_ref().unwrap().display(), label);
+        // Two packs writing one file would race, so the later one is refused
+        let key: PathBuf = output.components().filter(|c| *c != Component::CurDir).collect();
+        if let Some(first) = outputs.get(&key) {
+            failed.push(Item {
+                label,
+                result: Err(PackError::Settings(format!("same output as {}", first))),
+                secs: 0.0,
+            });
+            continue;
+        }
+        outputs.insert(key, label.clone());
+        match prepare_entry(item, target) {
+            Ok(job) => jobs_to_run.push((label, job)),
+            Err(e) => failed.push(Item {
+                label,
+                result: Err(e),
+                secs: 0.0,
+            }),
+        }
+    }
+
+    // Stub builds share the stub_loader directory, so they run one at a time
+    let mut stubs: HashMap<_, (Result<Vec<u8>, PackError>, f64)> = HashMap::new();
+    // Second builds for --check-reproducible, one per key that needs it
+    let mut rebuilt: HashMap<_, Result<Vec<u8>, PackError>> = HashMap::new();
+    for (label, job) in &jobs_to_run {
+        let key = job.stub.key();
+        if !stubs.contains_key(&key) {
+            info!("\nBuilding stub {} (for {})", stubs.len() + 1, label);
+            let start = Instant::now();
+            let stub = job.stub.build();
+            stubs.insert(key.clone(), (stub, start.elapsed().as_secs_f64()));
+        }
+        if job.args.check_reproducible && !rebuilt.contains_key(&key) {
+            info!("\nBuilding the stub again in a clean target directory (for {}'s --check-reproducible)", label);
+            rebuilt.insert(key, job.stub.rebuild());
+        }
+    }
+    info!(
+        "\nPacking {} executables with {} stub build(s), {} at a time",
+        jobs_to_run.len(),
+        stubs.len(),
+        jobs
+    );
+
+    let pool = ThreadPoolBuilder::new().num_threads(jobs.max(1)).build().unwrap();
+    let mut items: Vec<Item> = pool.install(|| {
+        jobs_to_run
+            .par_iter()
+            .map(|(label, job)| {
+                let start = Instant::now();
+                let key = job.stub.key();
+                let rebuilt = match rebuilt.get(&key).filter(|_| job.args.check_reproducible) {
+                    Some(Ok(stub)) => Ok(Some(stub.as_slice())),
+                    Some(Err(e)) => Err(e),
+                    None => Ok(None),
+                };
+                let result = match (&stubs[&key], rebuilt) {
+                    ((Ok(stub), build_secs), Ok(rebuilt)) => run(job, stub, rebuilt).map(|mut outcome| {
+                        // A shared build is reported by every pack that uses it
+                        outcome.report["stub"]["build_secs"] = json!(build_secs);
+                        outcome
+                    }),
+                    ((Err(e), _), _) | (_, Err(e)) => Err(e.clone()),
+                };
+                Item {
+                    label: label.clone(),
+                    result,
+                    secs: start.elapsed().as_secs_f64(),
+                }
+            })
+            .collect()
+    });
+    items.extend(failed);
+    print_summary(&items);
+    if let Some(path) = report_path {
+        report::write(path, &batch_report(&items, start.elapsed().as_secs_f64()))?;
+    }
+
+    let failures: Vec<(&String, &PackError)> =
+        items.iter().filter_map(|item| item.result.as_ref().err().map(|e| (&item.label, e))).collect();
+    for (label, e) in &failures {
+        error!("{}: {}", label, e);
+    }
+    match failures.first() {
+        Some((_, e)) => Err(PackError::Batch {
+            failed: failures.len(),
+            total: items.len(),
+            exit_code: e.exit_code(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Every entry's pack report, or its error, in summary order.
+fn batch_report(items: &[Item], secs: f64) -> Value {
+    let reports: Vec<Value> = items
+        .iter()
+        .map(|item| match &item.result {
+            Ok(outcome) => json!({
+                "status": "ok",
+                "report": outcome.report,
+            }),
+            Err(e) => json!({
+                "status": "failed",
+                "output": item.label,
+                "error": e.to_string(),
+                "exit_code": e.exit_code(),
+            }),
+        })
+        .collect();
+    let ok = items.iter().filter(|item| item.result.is_ok()).count();
+    json!({
+        "report_version": REPORT_VERSION,
+        "execompress_version": env!("CARGO_PKG_VERSION"),
+        "duration_secs": secs,
+        "packed": ok,
+        "failed": items.len() - ok,
+        "items": reports,
+    })
+}
+
+/// One row per entry: sizes of the input and the packed output, and the
+/// compressed size of the main executable as a share of the input.
+fn print_summary(items: &[Item]) {
+    let width = items.iter().map(|item| item.label.len()).max().unwrap_or(0).max(6);
+    println!("\n  {:<7} {:<width$} {:>12} {:>12} {:>7} {:>8}", "status", "output", "input", "packed", "ratio", "time");
+    for item in items {
+        match &item.result {
+            Ok(outcome) => println!(
+                "  {:<7} {:<width$} {:>12} {:>12} {:>6.2}% {:>7.1}s",
+                "ok",
+                item.label,
+                outcome.input_size,
+                outcome.size,
+                outcome.main_size as f64 / outcome.input_size as f64 * 100.0,
+                item.secs
+            ),
+            Err(_) => println!("  {:<7} {:<width$} {:>12} {:>12} {:>7} {:>8}", "FAILED", item.label, "-", "-", "-", "-"),
+        }
+    }
+    let ok = items.iter().filter(|item| item.result.is_ok()).count();
+    println!("{} packed, {} failed", ok, items.len() - ok);
+}
@@ -0,0 +1,328 @@
+use crate::codec::compress_entry;
+use crate::container::Container;
+use crate::error::PackError;
+use crate::extras::{self, Selection};
+use crate::filter;
+use crate::logging;
+use crate::stub::StubSettings;
+use execompress_codecs::{self as codecs, Tuning};
+use log::info;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// The stub extracts extra files on a fixed pool of this size
+const STUB_EXTRACT_THREADS: usize = 4;
+
+struct EntryTiming {
+    name: String,
+    compressed: usize,
+    original: usize,
+    secs: f64,
+}
+
+struct Decompression {
+    main_secs: f64,
+    extras_secs: f64,
+    entries: Vec<EntryTiming>,
+}
+
+struct BenchResult {
+    codec: &'static str,
+    level: u32,
+    output_size: usize,
+    pack_secs: f64,
+    decompression: Decompression,
+    launch_secs: Vec<f64>,
+}
+
+fn millis(secs: f64) -> f64 {
+    (secs * 1000.0 * 100.0).round() / 100.0
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    sorted.get(sorted.len() / 2).copied()
+}
+
+/// The files packing would take from `dir`, honouring its ignore file.
+fn read_extras(dir: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let files = extras::collect(Some(dir), &[], &[], &Selection::default())?;
+    let data = extras::read_all(&files, |file, input| {
+        let mut data = vec![];
+        input.read_to_end(&mut data).map_err(|e| PackError::extra(&file.source.path(), e))?;
+        Ok((file.name.clone(), data))
+    })?;
+    Ok(data)
+}
+
+/// Decompresses every entry the way the stub does: the main executable
+/// first, then all extra files on a pool of STUB_EXTRACT_THREADS threads.
+fn time_decompression(container: &Container) -> io::Result<Decompression> {
+    let start = Instant::now();
+    let main = container.main.decompress()?;
+    let main_secs = start.elapsed().as_secs_f64();
+
+    let mut entries = vec![EntryTiming {
+        name: container.main.name.clone(),
+        compressed: container.main.data.len(),
+        original: main.len(),
+        secs: main_secs,
+    }];
+
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(STUB_EXTRACT_THREADS)
+        .build()
+        .unwrap();
+    let start = Instant::now();
+    let extras = pool.install(|| {
+        container
+            .extras
+            .par_iter()
+            .map(|entry| {
+                let start = Instant::now();
+                let data = entry.decompress()?;
+                Ok(EntryTiming {
+                    name: entry.name.clone(),
+                    compressed: entry.data.len(),
+                    original: data.len(),
+                    secs: start.elapsed().as_secs_f64(),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+    let extras_secs = start.elapsed().as_secs_f64();
+    entries.extend(extras);
+
+    Ok(Decompression {
+        main_secs,
+        extras_secs,
+        entries,
+    })
+}
+
+/// Runs an executable to completion `runs` times and returns each wall time.
+/// `path` must be a file of our own, since it is made executable first. If it
+/// cannot be launched (a PE on Linux, say) it gets no launch times.
+#[cfg(target_os = "linux")]
+fn time_launch(path: &Path, runs: usize) -> Vec<f64> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Command, Stdio};
+
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o755)) {
+        log::warn!("Cannot make {} executable, skipping its launch times: {}", path.display(), e);
+        return vec![];
+    }
+    let mut times = vec![];
+    for _ in 0..runs {
+        let start = Instant::now();
+        let status = Command::new(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        let elapsed = start.elapsed().as_secs_f64();
+        match status {
+            Ok(status) => {
+                if !status.success() && times.is_empty() {
+                    log::warn!("{} {}; its launch times may not be comparable", path.display(), status);
+                }
+                times.push(elapsed);
+            }
+            Err(e) => {
+                log::warn!("Cannot launch {}, skipping its launch times: {}", path.display(), e);
+                return vec![];
+            }
+        }
+    }
+    times
+}
+
+#[cfg(not(target_os = "linux"))]
+fn time_launch(_path: &Path, _runs: usize) -> Vec<f64> {
+    vec![]
+}
+
+/// Packs `input` (and `extra_dir`) with every codec and level of the sweep
+/// and measures what each one costs at run time.
+pub fn bench(
+    input: &Path,
+    extra_dir: Option<&Path>,
+    parallel: usize,
+    runs: usize,
+    json_path: Option<&Path>,
+) -> io::Result<()> {
+    // The JSON goes to stdout alone; everything else is moved to stderr
+    let json_to_stdout = json_path == Some(Path::new("-"));
+    if json_to_stdout {
+        logging::reserve_stdout();
+    }
+    let input_data = fs::read(input)?;
+    let extras = match extra_dir {
+        Some(dir) if dir.is_dir() => read_extras(dir)?,
+        _ => vec![],
+    };
+    let extras_size: usize = extras.iter().map(|(_, d)| d.len()).sum();
+    let name = input.file_name().unwrap().to_string_lossy().to_string();
+
+    // Written like a plain pack's, so no resources are left from an earlier pack
+    info!("Building stub...");
+    let settings = StubSettings {
+        original_filename: name.clone(),
+        ..StubSettings::default()
+    };
+    let stub = settings.build()?;
+
+    let work_dir = std::env::temp_dir().join(format!("execompress-bench-{}", std::process::id()));
+    fs::create_dir_all(&work_dir)?;
+
+    // The input is launched from a copy so its own permissions are left alone
+    let input_copy = work_dir.join(&name);
+    fs::write(&input_copy, &input_data)?;
+    let baseline_launch = time_launch(&input_copy, runs);
+    let _ = fs::remove_file(&input_copy);
+
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(parallel)
+        .build()
+        .unwrap();
+    let tuning = Tuning::default();
+
+    let mut results = vec![];
+    for &codec in codecs::registry() {
+        for &level in codec.sweep() {
+            info!("Packing with {} level {}...", codec.name(), level);
+
+            let start = Instant::now();
+            let main = compress_entry(name.clone(), codec, level, &tuning, parallel as u32, filter::detect(&input_data), &input_data)?;
+            let packed_extras = pool.install(|| {
+                extras
+                    .par_iter()
+                    .map(|(name, data)| compress_entry(name.clone(), codec, level, &tuning, 1, filter::detect(data), data))
+                    .collect::<io::Result<Vec<_>>>()
+            })?;
+            let pack_secs = start.elapsed().as_secs_f64();
+
+            let container = Container {
+                stub: stub.clone(),
+                cleanup: true,
+                metadata: BTreeMap::from([
+                    ("codec".to_string(), codec.name().to_string()),
+                    ("level".to_string(), level.to_string()),
+                ]),
+                main,
+                extras: packed_extras,
+            };
+            let bytes = container.to_bytes();
+            let packed_path: PathBuf = work_dir.join(format!("{}-{}-{}", codec.name(), level, name));
+            fs::write(&packed_path, &bytes)?;
+
+            let decompression = time_decompression(&container)?;
+            let launch_secs = time_launch(&packed_path, runs);
+            let _ = fs::remove_file(&packed_path);
+
+            results.push(BenchResult {
+                codec: codec.name(),
+                level,
+                output_size: bytes.len(),
+                pack_secs,
+                decompression,
+                launch_secs,
+            });
+        }
+    }
+    let _ = fs::remove_dir_all(&work_dir);
+
+    let original_size = input_data.len() + extras_size;
+    // Writing to a String cannot fail
+    let mut table = String::new();
+    writeln!(
+        table,
+        "\nInput: {} ({} bytes) + {} extra files ({} bytes), stub {} bytes",
+        input.display(),
+        input_data.len(),
+        extras.len(),
+        extras_size,
+        stub.len()
+    )
+    .unwrap();
+    if let Some(baseline) = median(&baseline_launch) {
+        writeln!(table, "Unpacked launch: {:.2} ms (median of {})", millis(baseline), runs).unwrap();
+    }
+    writeln!(
+        table,
+        "\n{:<8} {:>5} {:>12} {:>8} {:>10} {:>10} {:>11} {:>12} {:>11}",
+        "codec", "level", "output", "ratio", "pack s", "main ms", "extras ms", "total ms", "launch ms"
+    )
+    .unwrap();
+    for r in &results {
+        let launch = median(&r.launch_secs)
+            .map(|s| format!("{:.2}", millis(s)))
+            .unwrap_or_else(|| "-".to_string());
+        writeln!(
+            table,
+            "{:<8} {:>5} {:>12} {:>7.2}% {:>10.2} {:>10.2} {:>11.2} {:>12.2} {:>11}",
+            r.codec,
+            r.level,
+            r.output_size,
+            r.output_size as f64 / original_size.max(1) as f64 * 100.0,
+            r.pack_secs,
+            millis(r.decompression.main_secs),
+            millis(r.decompression.extras_secs),
+            millis(r.decompression.main_secs + r.decompression.extras_secs),
+            launch
+        )
+        .unwrap();
+    }
+    if json_to_stdout {
+        eprint!("{}", table);
+    } else {
+        print!("{}", table);
+    }
+
+    if let Some(json_path) = json_path {
+        let report = json!({
+            "input": input.display().to_string(),
+            "input_size": input_data.len(),
+            "extra_files": extras.len(),
+            "extra_size": extras_size,
+            "stub_size": stub.len(),
+            "runs": runs,
+            "unpacked_launch_ms": median(&baseline_launch).map(millis),
+            "results": results.iter().map(|r| json!({
+                "codec": r.codec,
+                "level": r.level,
+                "output_size": r.output_size,
+                "pack_secs": r.pack_secs,
+                "decompress_ms": {
+                    "main": millis(r.decompression.main_secs),
+                    "extras": millis(r.decompression.extras_secs),
+                    "total": millis(r.decompression.main_secs + r.decompression.extras_secs),
+                },
+                "entries": r.decompression.entries.iter().map(|e| json!({
+                    "name": e.name,
+                    "compressed": e.compressed,
+                    "original": e.original,
+                    "decompress_ms": millis(e.secs),
+                })).collect::<Vec<Value>>(),
+                "launch_ms": r.launch_secs.iter().map(|s| millis(*s)).collect::<Vec<f64>>(),
+                "launch_median_ms": median(&r.launch_secs).map(millis),
+            })).collect::<Vec<Value>>(),
+        });
+        let text = serde_json::to_string_pretty(&report).unwrap();
+        if json_to_stdout {
+            println!("{}", text);
+        } else {
+            fs::write(json_path, text)?;
+            info!("\nJSON results written to {:?}", json_path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use crate::container::Entry;
+use crate::filter::{self, Filter};
+use execompress_codecs::{Codec, EncodeOptions, Tuning};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+/// Compresses `input` into `output` with the branch filter applied first and
+/// returns the entry header params the stub needs to restore it. For codecs
+/// that chain it (xz) the x86 filter becomes part of the codec's own stream;
+/// otherwise the data is transformed on the way in and the stub reverses it
+/// after decompression. The codec ID is recorded so the stub need not guess.
+pub fn compress_stream(
+    codec: &dyn Codec,
+    level: u32,
+    tuning: &Tuning,
+    threads: u32,
+    filter: Option<Filter>,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> io::Result<BTreeMap<String, String>> {
+    let mut options = EncodeOptions {
+        level,
+        tuning,
+        threads,
+        x86_filter: false,
+    };
+    let recorded = match filter {
+        None => {
+            codec.encode(&options, input, output)?;
+            None
+        }
+        Some(Filter::X86) if codec.chains_x86() => {
+            options.x86_filter = true;
+            codec.encode(&options, input, output)?;
+            Some("xz-x86".to_string())
+        }
+        Some(filter) => {
+            codec.encode(&options, &mut filter::Encoder::new(filter, input), output)?;
+            Some(filter.name().to_string())
+        }
+    };
+
+    let mut params = BTreeMap::new();
+    params.insert("codec".to_string(), codec.id().to_string());
+    if let Some(filter) = recorded {
+        params.insert("filter".to_string(), filter);
+    }
+    Ok(params)
+}
+
+/// Copies an extra file for a solid block with its branch filter applied
+/// and returns the params to restore it. The filter is reversed file by
+/// file after the block is decompressed.
+pub fn stage_solid(filter: Option<Filter>, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<BTreeMap<String, String>> {
+    let mut params = BTreeMap::new();
+    match filter {
+        Some(filter) => {
+            io::copy(&mut filter::Encoder::new(filter, input), output)?;
+            params.insert("filter".to_string(), filter.name().to_string());
+        }
+        None => {
+            io::copy(input, output)?;
+        }
+    }
+    Ok(params)
+}
+
+/// `compress_stream` for data already in memory.
+pub fn compress_entry(
+    name: String,
+    codec: &dyn Codec,
+    level: u32,
+    tuning: &Tuning,
+    threads: u32,
+    filter: Option<Filter>,
+    data: &[u8],
+) -> io::Result<Entry> {
+    let mut compressed = vec![];
+    let params = compress_stream(codec, level, tuning, threads, filter, &mut &data[..], &mut compressed)?;
+    let mut entry = Entry::new(name, compressed);
+    entry.params = params;
+    Ok(entry)
+}
@@ -0,0 +1,254 @@
+use crate::container::{Container, Entry};
+use crate::patch::sha256_hex;
+use crate::pe::{PeFile, IMAGE_SUBSYSTEM_WINDOWS_GUI, RT_MANIFEST};
+use crate::version_extractor::{extract_version_info_from_bytes, VersionInfo};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Sizes and identity of one container entry after decompression.
+#[derive(Debug, Clone)]
+pub struct EntrySummary {
+    pub name: String,
+    pub codec: &'static str,
+    pub filter: Option<String>,
+    pub compressed: u64,
+    pub original: u64,
+    pub sha256: String,
+}
+
+/// Everything `compare` looks at in a packed executable.
+#[derive(Debug)]
+pub struct Summary {
+    pub file_size: u64,
+    pub stub_size: u64,
+    pub gui: bool,
+    pub cleanup: bool,
+    pub manifest: bool,
+    pub require_admin: bool,
+    pub machine: Option<u16>,
+    pub version: VersionInfo,
+    pub metadata: BTreeMap<String, String>,
+    pub main: EntrySummary,
+    pub extras: Vec<EntrySummary>,
+}
+
+fn summarize_entry(entry: &Entry, original: &[u8]) -> EntrySummary {
+    EntrySummary {
+        name: entry.name.clone(),
+        codec: entry.codec(),
+        filter: entry.filter().map(|f| f.to_string()),
+        compressed: entry.data.len() as u64,
+        original: original.len() as u64,
+        sha256: sha256_hex(original),
+    }
+}
+
+fn failed(entry: &Entry) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("Failed to decompress {}: {}", entry.name, e))
+}
+
+pub fn summarize(path: &Path) -> io::Result<Summary> {
+    let bytes = fs::read(path)?;
+    let container = Container::parse(&bytes)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+    let main_data = container.main.decompress().map_err(failed(&container.main))?;
+    let main = summarize_entry(&container.main, &main_data);
+    let extras = container
+        .extra_groups()
+        .into_par_iter()
+        .map(|group| {
+            let entries = &container.extras[group.clone()];
+            let data = container.decompress_group(group).map_err(failed(&entries[0]))?;
+            Ok(entries.iter().zip(data).map(|(e, data)| summarize_entry(e, &data)).collect::<Vec<_>>())
+        })
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Flags and version info live in the stub's PE headers and resources
+    let stub = PeFile::parse(&container.stub);
+    let manifest = stub
+        .as_ref()
+        .and_then(|pe| pe.find_resource(RT_MANIFEST))
+        .map(|m| String::from_utf8_lossy(m).to_string());
+
+    Ok(Summary {
+        file_size: bytes.len() as u64,
+        stub_size: container.stub.len() as u64,
+        gui: stub.as_ref().map(|pe| pe.subsystem == IMAGE_SUBSYSTEM_WINDOWS_GUI).unwrap_or(false),
+        cleanup: container.cleanup,
+        require_admin: manifest.as_ref().map(|m| m.contains("requireAdministrator")).unwrap_or(false),
+        manifest: manifest.is_some(),
+        machine: PeFile::parse(&main_data).map(|pe| pe.machine),
+        version: extract_version_info_from_bytes(&container.stub).unwrap_or_default(),
+        metadata: container.metadata,
+        main,
+        extras,
+    })
+}
+
+pub fn machine_name(machine: u16) -> String {
+    match machine {
+        0x014c => "x86".to_string(),
+        0x8664 => "x64".to_string(),
+        0xaa64 => "ARM64".to_string(),
+        0x01c4 => "ARMv7".to_string(),
+        other => format!("0x{:04X}", other),
+    }
+}
+
+fn signed(delta: i64) -> String {
+    format!("{:+}", delta)
+}
+
+fn delta(a: u64, b: u64) -> i64 {
+    b as i64 - a as i64
+}
+
+fn metadata(summary: &Summary) -> Vec<(String, String)> {
+    let v = &summary.version;
+    let text = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".to_string());
+    let mut list = vec![
+        ("codec", summary.main.codec.to_string()),
+        ("gui", summary.gui.to_string()),
+        ("cleanup", summary.cleanup.to_string()),
+        ("manifest", summary.manifest.to_string()),
+        ("require admin", summary.require_admin.to_string()),
+        ("architecture", summary.machine.map(machine_name).unwrap_or_else(|| "-".to_string())),
+        ("executable name", summary.main.name.clone()),
+        ("ProductName", text(&v.product_name)),
+        ("CompanyName", text(&v.company_name)),
+        ("FileDescription", text(&v.file_description)),
+        ("ProductVersion", text(&v.product_version)),
+        ("FileVersion", text(&v.file_version)),
+        ("LegalCopyright", text(&v.copyright)),
+    ];
+    list.retain(|(key, _)| !summary.metadata.contains_key(*key));
+    let mut list: Vec<(String, String)> = list.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+    list.extend(summary.metadata.iter().map(|(k, v)| (k.clone(), v.clone())));
+    list
+}
+
+/// Prints the differences between two packed executables: metadata first,
+/// then added, removed and changed entries ordered by compressed size delta.
+pub fn compare(a_path: &Path, b_path: &Path) -> io::Result<()> {
+    let a = summarize(a_path)?;
+    let b = summarize(b_path)?;
+
+    println!(
+        "Comparing {} ({} bytes) -> {} ({} bytes): {} bytes",
+        a_path.display(),
+        a.file_size,
+        b_path.display(),
+        b.file_size,
+        signed(delta(a.file_size, b.file_size))
+    );
+
+    println!("\nMetadata");
+    let mut metadata_changed = false;
+    let (old_metadata, new_metadata) = (metadata(&a), metadata(&b));
+    let mut keys: Vec<&String> = old_metadata.iter().chain(&new_metadata).map(|(k, _)| k).collect();
+    let mut seen = std::collections::HashSet::new();
+    keys.retain(|k| seen.insert(*k));
+    let value = |list: &Vec<(String, String)>, key: &String| {
+        list.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()).unwrap_or_else(|| "-".to_string())
+    };
+    for key in keys {
+        let (old, new) = (value(&old_metadata, key), value(&new_metadata, key));
+        if old != new {
+            println!("  {:<18} {} -> {}", key, old, new);
+            metadata_changed = true;
+        }
+    }
+    if a.stub_size != b.stub_size {
+        println!("  {:<18} {} -> {} bytes", "stub size", a.stub_size, b.stub_size);
+        metadata_changed = true;
+    }
+    if !metadata_changed {
+        println!("  (no differences)");
+    }
+
+    // Key the main executable separately so a renamed output still lines up
+    let mut old_entries: BTreeMap<String, &EntrySummary> = BTreeMap::new();
+    let mut new_entries: BTreeMap<String, &EntrySummary> = BTreeMap::new();
+    old_entries.insert(String::new(), &a.main);
+    new_entries.insert(String::new(), &b.main);
+    old_entries.extend(a.extras.iter().map(|e| (e.name.clone(), e)));
+    new_entries.extend(b.extras.iter().map(|e| (e.name.clone(), e)));
+
+    // (marker, label, compressed delta, original delta, detail)
+    let mut rows: Vec<(char, String, i64, i64, String)> = vec![];
+    let mut unchanged = 0;
+    for (key, new) in &new_entries {
+        let label = if key.is_empty() { format!("{} (main)", new.name) } else { key.clone() };
+        match old_entries.get(key) {
+            None => rows.push(('+', label, new.compressed as i64, new.original as i64, new.codec.to_string())),
+            Some(old) if old.sha256 == new.sha256 && old.compressed == new.compressed && old.codec == new.codec && old.filter == new.filter => {
+                unchanged += 1;
+            }
+            Some(old) => {
+                let mut detail = format!(
+                    "{} -> {} compressed, {} -> {} original",
+                    old.compressed, new.compressed, old.original, new.original
+                );
+                if old.codec != new.codec {
+                    detail.push_str(&format!(", {} -> {}", old.codec, new.codec));
+                }
+                if old.filter != new.filter {
+                    let name = |f: &Option<String>| f.clone().unwrap_or_else(|| "no filter".to_string());
+                    detail.push_str(&format!(", {} -> {}", name(&old.filter), name(&new.filter)));
+                }
+                if old.sha256 == new.sha256 {
+                    detail.push_str(", content identical");
+                }
+                rows.push((
+                    '~',
+                    label,
+                    delta(old.compressed, new.compressed),
+                    delta(old.original, new.original),
+                    detail,
+                ));
+            }
+        }
+    }
+    for (key, old) in &old_entries {
+        if !new_entries.contains_key(key) {
+            rows.push(('-', key.clone(), -(old.compressed as i64), -(old.original as i64), old.codec.to_string()));
+        }
+    }
+    rows.sort_by(|x, y| y.2.abs().cmp(&x.2.abs()).then_with(|| x.1.cmp(&y.1)));
+
+    println!("\nEntries{:<33} {:>14} {:>14}", "", "compressed", "original");
+    for (marker, label, compressed, original, detail) in &rows {
+        println!("  {} {:<36} {:>14} {:>14}  {}", marker, label, signed(*compressed), signed(*original), detail);
+    }
+    if rows.is_empty() {
+        println!("  (no differences)");
+    }
+    if unchanged > 0 {
+        println!("  = {} unchanged entries", unchanged);
+    }
+
+    let total = |s: &Summary| {
+        s.extras.iter().chain(std::iter::once(&s.main)).fold((0u64, 0u64), |(c, o), e| (c + e.compressed, o + e.original))
+    };
+    let (a_compressed, a_original) = total(&a);
+    let (b_compressed, b_original) = total(&b);
+    println!(
+        "\nTotal: {} -> {} entries, compressed {} -> {} ({}), original {} -> {} ({})",
+        a.extras.len() + 1,
+        b.extras.len() + 1,
+        a_compressed,
+        b_compressed,
+        signed(delta(a_compressed, b_compressed)),
+        a_original,
+        b_original,
+        signed(delta(a_original, b_original))
+    );
+    Ok(())
+}
@@ -0,0 +1,420 @@
+use crate::profile::Profile;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Project config file looked up in the current directory
+pub const CONFIG_FILE: &str = "execompress.toml";
+
+/// Settings read from execompress.toml. Every key is optional and mirrors a
+/// pack flag; flags given on the command line win. `[target.<name>]`
+/// sections hold the same keys (except `profile`, `target` and `batch`) and,
+/// when selected with --target, override the top level. `[[batch]]` entries
+/// hold them too, each one a pack of its own for `execompress batch`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    /// Codec name or `auto`, as --algo
+    pub codec: Option<String>,
+    pub level: Option<u32>,
+    /// Profile applied to whatever neither the command line nor this file sets
+    pub use_profile: Option<String>,
+    pub objective: Option<String>,
+    pub time_budget: Option<f64>,
+    /// Bytes, with an optional K/M/G suffix
+    pub max_output_size: Option<String>,
+    pub parallel: Option<usize>,
+    /// Apply the x86/ARM64 branch filter
+    pub bcj: Option<bool>,
+    /// Compress the extra files as one stream
+    pub solid: Option<bool>,
+    pub xz_extreme: Option<bool>,
+    /// Bytes, with an optional K/M/G suffix
+    pub xz_dict_size: Option<String>,
+    pub zstd_long: Option<u32>,
+    pub brotli_lgwin: Option<u32>,
+    pub brotli_large_window: Option<bool>,
+    /// Per-file codec rules for extra files, same syntax as --rule
+    pub rules: Option<Vec<String>>,
+    pub extras: Option<Extras>,
+    pub version: Option<VersionStrings>,
+    pub manifest: Option<Manifest>,
+    pub runtime: Option<Runtime>,
+
+    /// Named profiles, `[profile.<name>]`
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+    /// Per-target overrides, `[target.<name>]`
+    #[serde(default)]
+    pub target: BTreeMap<String, Config>,
+    /// Packs run by `batch`, `[[batch]]`, each over the top level
+    #[serde(default)]
+    pub batch: Vec<Config>,
+}
+
+/// `[extras]`: files packed next to the main executable.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Extras {
+    /// Directory packed as a whole, as --extra-dir
+    pub dir: Option<PathBuf>,
+    /// Individual files or directories placed at a chosen path in the bundle
+    pub map: Option<Vec<Mapping>>,
+    /// Globs added to --include and --exclude
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+}
+
+/// A file, or a directory with everything under it, stored at `dest`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    pub source: PathBuf,
+    /// Path inside the bundle; defaults to the source's own name
+    pub dest: Option<String>,
+}
+
+/// `[version]`: version info strings for the stub, as --product-name etc.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VersionStrings {
+    pub product_name: Option<String>,
+    pub company_name: Option<String>,
+    pub file_description: Option<String>,
+    pub product_version: Option<String>,
+    pub file_version: Option<String>,
+    pub copyright: Option<String>,
+    /// Fill the strings not set from the input executable, as --input-version-info
+    pub from_input: Option<bool>,
+}
+
+/// `[manifest]`: the embedded Windows manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// As --manifest
+    pub embed: Option<bool>,
+    pub require_admin: Option<bool>,
+}
+
+/// `[runtime]`: how the stub behaves when launched.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Runtime {
+    pub gui: Option<bool>,
+    pub cleanup: Option<bool>,
+}
+
+impl Mapping {
+    /// Destination inside the bundle, with `/` separators and without empty
+    /// or `.` components.
+    pub fn dest(&self) -> String {
+        match &self.dest {
+            Some(dest) => {
+                let dest = dest.replace('\\', "/");
+                let parts: Vec<&str> = dest.split('/').filter(|p| !p.is_empty() && *p != ".").collect();
+                parts.join("/")
+            }
+            None => self
+                .source
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Checks that the destination stays inside the bundle.
+    pub fn check_dest(&self) -> Result<(), String> {
+        let dest = self.dest();
+        if !dest.is_empty() && Path::new(&dest).components().all(|c| matches!(c, Component::Normal(_))) {
+            return Ok(());
+        }
+        match &self.dest {
+            Some(given) => Err(format!("'{}' is not a relative path inside the bundle", given)),
+            None => Err(format!("{} has no file name to use as its destination", self.source.display())),
+        }
+    }
+}
+
+// Fills every field not set in `self` from `base`
+macro_rules! or_fields {
+    ($self:ident, $base:ident, $($field:ident),*) => {
+        $(let $field = $self.$field.or($base.$field);)*
+    };
+}
+
+impl Extras {
+    fn or(self, base: Extras) -> Extras {
+        or_fields!(self, base, dir, map, include, exclude);
+        Extras {
+            dir,
+            map,
+            include,
+            exclude,
+        }
+    }
+}
+
+impl VersionStrings {
+    fn or(self, base: VersionStrings) -> VersionStrings {
+        or_fields!(self, base, product_name, company_name, file_description, product_version, file_version, copyright, from_input);
+        VersionStrings {
+            product_name,
+            company_name,
+            file_description,
+            product_version,
+            file_version,
+            copyright,
+            from_input,
+        }
+    }
+}
+
+impl Manifest {
+    fn or(self, base: Manifest) -> Manifest {
+        or_fields!(self, base, embed, require_admin);
+        Manifest { embed, require_admin }
+    }
+}
+
+impl Runtime {
+    fn or(self, base: Runtime) -> Runtime {
+        or_fields!(self, base, gui, cleanup);
+        Runtime { gui, cleanup }
+    }
+}
+
+/// Merges two optional sections field by field.
+fn or_section<T>(section: Option<T>, base: Option<T>, or: fn(T, T) -> T) -> Option<T> {
+    match (section, base) {
+        (Some(section), Some(base)) => Some(or(section, base)),
+        (section, base) => section.or(base),
+    }
+}
+
+impl Config {
+    /// The settings in effect for `target`: its section over the top level.
+    pub fn for_target(&self, target: Option<&str>) -> Result<Config, String> {
+        let Some(name) = target else {
+            return Ok(self.clone());
+        };
+        let Some(section) = self.target.get(name).cloned() else {
+            let known: Vec<&str> = self.target.keys().map(|k| k.as_str()).collect();
+            return Err(if known.is_empty() {
+                format!("Unknown target '{}': the config file has no [target.<name>] sections", name)
+            } else {
+                format!("Unknown target '{}'. Available targets: {}", name, known.join(", "))
+            });
+        };
+        Ok(section.over(self.clone()))
+    }
+
+    /// The `[[batch]]` entries, each with `target`'s section and the top
+    /// level filling in what it leaves unset.
+    pub fn batch_items(&self, target: Option<&str>) -> Result<Vec<Config>, String> {
+        let base = self.for_target(target)?;
+        Ok(self.batch.iter().map(|item| item.clone().over(base.clone())).collect())
+    }
+
+    /// `self`'s settings, with `base` filling in what it leaves unset.
+    /// Profiles come from `base`, which must be the top level.
+    fn over(self, base: Config) -> Config {
+        let section = self;
+        or_fields!(
+            section, base, input, output, codec, level, use_profile, objective, time_budget, max_output_size, parallel,
+            bcj, solid, xz_extreme, xz_dict_size, zstd_long, brotli_lgwin, brotli_large_window, rules
+        );
+        Config {
+            input,
+            output,
+            codec,
+            level,
+            use_profile,
+            objective,
+            time_budget,
+            max_output_size,
+            parallel,
+            bcj,
+            solid,
+            xz_extreme,
+            xz_dict_size,
+            zstd_long,
+            brotli_lgwin,
+            brotli_large_window,
+            rules,
+            extras: or_section(section.extras, base.extras, Extras::or),
+            version: or_section(section.version, base.version, VersionStrings::or),
+            manifest: or_section(section.manifest, base.manifest, Manifest::or),
+            runtime: or_section(section.runtime, base.runtime, Runtime::or),
+            profile: base.profile,
+            target: BTreeMap::new(),
+            batch: vec![],
+        }
+    }
+
+    /// Makes relative paths relative to the config file's directory.
+    fn resolve_paths(&mut self, dir: &Path) {
+        for path in [&mut self.input, &mut self.output].into_iter().flatten() {
+            *path = dir.join(&*path);
+        }
+        if let Some(extras) = &mut self.extras {
+            if let Some(path) = &mut extras.dir {
+                *path = dir.join(&*path);
+            }
+            for mapping in extras.map.iter_mut().flatten() {
+                mapping.source = dir.join(&mapping.source);
+            }
+        }
+        for section in self.target.values_mut().chain(self.batch.iter_mut()) {
+            section.resolve_paths(dir);
+        }
+    }
+
+    /// Checks the values serde cannot, naming the offending key.
+    fn validate(&self, prefix: &str) -> Result<(), String> {
+        let key = |name: &str| format!("{}{}", prefix, name);
+
+        if let Some(codec) = &self.codec {
+            if codec != "auto" && execompress_codecs::by_name(codec).is_none() {
+                let mut known: Vec<&str> = execompress_codecs::registry().iter().map(|c| c.name()).collect();
+                known.push("auto");
+                return Err(format!("{}: unknown codec '{}' (expected one of {})", key("codec"), codec, known.join(", ")));
+            }
+        }
+        if let (Some(level), Some(codec)) = (self.level, self.codec.as_deref().and_then(execompress_codecs::by_name)) {
+            if !codec.levels().contains(&level) {
+                return Err(format!(
+                    "{}: {} supports levels {}-{}, got {}",
+                    key("level"),
+                    codec.name(),
+                    codec.levels().start(),
+                    codec.levels().end(),
+                    level
+                ));
+            }
+        }
+        if let Some(objective) = &self.objective {
+            if !["size", "balanced", "startup"].contains(&objective.as_str()) {
+                return Err(format!("{}: unknown objective '{}' (expected size, balanced or startup)", key("objective"), objective));
+            }
+        }
+        if let Some(secs) = self.time_budget {
+            if secs.is_nan() || secs <= 0.0 {
+                return Err(format!("{}: must be a positive number of seconds", key("time_budget")));
+            }
+        }
+        if self.parallel == Some(0) {
+            return Err(format!("{}: must be at least 1", key("parallel")));
+        }
+        for (name, value) in [("max_output_size", &self.max_output_size), ("xz_dict_size", &self.xz_dict_size)] {
+            if let Some(value) = value {
+                crate::parse_size(value).map_err(|e| format!("{}: {}", key(name), e))?;
+            }
+        }
+        for (name, globs) in self.extras.iter().flat_map(|e| [("include", &e.include), ("exclude", &e.exclude)]) {
+            for (i, glob) in globs.iter().flatten().enumerate() {
+                globset::Glob::new(glob).map_err(|e| format!("{}: {}", key(&format!("extras.{}[{}]", name, i)), e))?;
+            }
+        }
+        for (i, mapping) in self.extras.iter().flat_map(|e| e.map.iter().flatten()).enumerate() {
+            mapping
+                .check_dest()
+                .map_err(|e| format!("{}: {}", key(&format!("extras.map[{}].dest", i)), e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads the config file at `path`, or `execompress.toml` in the current
+/// directory. Without an explicit path a missing file is an empty config.
+pub fn load(path: Option<&Path>) -> io::Result<Config> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(CONFIG_FILE).is_file() => Path::new(CONFIG_FILE),
+        None => return Ok(Config::default()),
+    };
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message));
+
+    let text = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let mut config: Config = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+
+    config.validate("").map_err(invalid)?;
+    let sections = config.target.iter().map(|(name, section)| (format!("target.{}.", name), section));
+    let items = config.batch.iter().enumerate().map(|(i, item)| (format!("batch[{}].", i), item));
+    for (prefix, section) in sections.chain(items) {
+        if !section.profile.is_empty() {
+            return Err(invalid(format!("{}profile: profiles can only be defined at the top level", prefix)));
+        }
+        if !section.target.is_empty() {
+            return Err(invalid(format!("{}target: targets can only be defined at the top level", prefix)));
+        }
+        if !section.batch.is_empty() {
+            return Err(invalid(format!("{}batch: batch entries can only be defined at the top level", prefix)));
+        }
+        // Checked merged, as a section's level may be meant for the top-level codec
+        section.clone().over(config.clone()).validate(&prefix).map_err(invalid)?;
+    }
+
+    config.resolve_paths(path.parent().unwrap_or(Path::new("")));
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_str(text: &str) -> Result<Config, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE);
+        fs::write(&path, text).unwrap();
+        load(Some(&path)).map_err(|e| e.to_string())
+    }
+
+    fn error(text: &str) -> String {
+        load_str(text).expect_err(text)
+    }
+
+    #[test]
+    fn accepts_known_keys() {
+        let config = load_str(
+            r#"
+            codec = "zstd"
+            level = 19
+            [extras]
+            dir = "assets"
+            [profile.assets]
+            codec = "brotli"
+            bcj = false
+            [target.win]
+            level = 9
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.level, Some(19));
+        assert_eq!(config.profile["assets"].bcj, Some(false));
+        assert_eq!(config.for_target(Some("win")).unwrap().level, Some(9));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(error("levle = 3").contains("unknown field `levle`"));
+        assert!(error("[extras]\ndirr = \"assets\"").contains("unknown field `dirr`"));
+        assert!(error("[version]\nproduct = \"x\"").contains("unknown field `product`"));
+        assert!(error("[profile.fast]\ncodecs = \"zstd\"").contains("unknown field `codecs`"));
+        assert!(error("[target.win]\nguii = true").contains("unknown field `guii`"));
+        assert!(error("[[batch]]\ninptu = \"a.exe\"").contains("unknown field `inptu`"));
+    }
+
+    #[test]
+    fn rejects_invalid_values_by_key() {
+        assert!(error("codec = \"lzma\"\nlevel = 12").contains("level: lzma supports levels 0-9, got 12"));
+        assert!(error("codec = \"lzma\"\n[target.win]\nlevel = 12").contains("target.win.level: lzma supports levels 0-9, got 12"));
+        assert!(error("xz_dict_size = \"64Q\"").contains("xz_dict_size: Unknown size suffix 'Q'"));
+        assert!(error("parallel = 0").contains("parallel: must be at least 1"));
+        assert!(error("[target.win.profile.x]\nlevel = 1").contains("target.win.profile: profiles can only be defined at the top level"));
+    }
+}
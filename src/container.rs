@@ -0,0 +1,534 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use crate::filter::Filter;
+use execompress_codecs::{self as codecs, Codec};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+// Markers written after the stub executable, in the order they appear
+const MARKER_FILENAME: &[u8] = b"\n--XFILENAMEX--\n";
+const MARKER_CLEANUP: &[u8] = b"\n--CLEANUP--\n";
+const MARKER_METADATA: &[u8] = b"\n--METADATA--\n";
+const MARKER_FILE_CONTENT: &[u8] = b"\n--FILE-CONTENT--\n";
+const MARKER_EXTRA: &[u8] = b"\n--EXTRA-FILE--\n";
+
+/// A single compressed payload stored in a packed executable.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Original filename for the main executable, relative path for extra files
+    pub name: String,
+    /// Compressed bytes exactly as stored in the container
+    pub data: Vec<u8>,
+    /// `key=value` pairs written after the length in the entry header
+    /// (e.g. `codec=2 filter=x86`), needed by the stub to restore the original bytes
+    pub params: BTreeMap<String, String>,
+}
+
+impl Entry {
+    pub fn new(name: String, data: Vec<u8>) -> Self {
+        Entry {
+            name,
+            data,
+            params: BTreeMap::new(),
+        }
+    }
+
+    /// Codec from the recorded ID, or detected from the stream header for
+    /// entries written before IDs were recorded.
+    fn resolve_codec(&self) -> io::Result<&'static dyn Codec> {
+        match self.params.get("codec") {
+            Some(id) => id.parse().ok().and_then(codecs::by_id).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} uses codec ID {}, which is not enabled in this build", self.name, id),
+                )
+            }),
+            None => Ok(codecs::detect(&self.data)),
+        }
+    }
+
+    pub fn codec(&self) -> &'static str {
+        if self.link().is_some() {
+            return "symlink";
+        }
+        self.resolve_codec().map(|c| c.name()).unwrap_or("unknown")
+    }
+
+    /// Target of a symlink packed from an archive; such entries have no data.
+    pub fn link(&self) -> Option<String> {
+        self.params.get("link").and_then(|target| link_target(target))
+    }
+
+    /// Unix permission bits recorded for a file packed from an archive.
+    pub fn mode(&self) -> Option<u32> {
+        self.params.get("mode").and_then(|mode| u32::from_str_radix(mode, 8).ok())
+    }
+
+    /// Branch filter applied before compression, as recorded in the header.
+    /// `xz-` prefixed filters are part of the xz stream itself.
+    pub fn filter(&self) -> Option<&str> {
+        self.params.get("filter").map(|f| f.as_str())
+    }
+
+    /// Original size of a file stored in a solid block. The first file of a
+    /// block holds the files that follow it compressed as one stream; the
+    /// others have no data of their own.
+    pub fn solid(&self) -> Option<u64> {
+        self.params.get("solid").and_then(|size| size.parse().ok())
+    }
+
+    /// The decompressed stream, before the branch filter is reversed.
+    pub fn decode_stream(&self) -> io::Result<Vec<u8>> {
+        codecs::decode(self.resolve_codec()?, &self.data)
+    }
+
+    /// The original bytes; for a symlink, its target. Files in a solid block
+    /// are decompressed together by [`Container::decompress_group`].
+    pub fn decompress(&self) -> io::Result<Vec<u8>> {
+        if let Some(target) = self.link() {
+            return Ok(target.into_bytes());
+        }
+        if self.solid().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is stored in a solid block", self.name),
+            ));
+        }
+        let mut data = self.decode_stream()?;
+        self.unfilter(&mut data)?;
+        Ok(data)
+    }
+
+    fn unfilter(&self, data: &mut [u8]) -> io::Result<()> {
+        if let Some(name) = self.filter() {
+            if !name.starts_with("xz-") {
+                let filter = Filter::from_name(name).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("Unknown filter '{}' for {}", name, self.name))
+                })?;
+                filter.decode(data);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The layout of a packed executable: the stub loader followed by the payload.
+#[derive(Debug, Clone)]
+pub struct Container {
+    pub stub: Vec<u8>,
+    pub cleanup: bool,
+    /// Pack settings recorded for inspection; ignored by the stub
+    pub metadata: BTreeMap<String, String>,
+    pub main: Entry,
+    pub extras: Vec<Entry>,
+}
+
+impl Container {
+    /// Locates the payload appended to the stub. The stub itself contains the
+    /// marker strings, so every candidate is tried until one parses through to
+    /// the end of the file.
+    pub fn parse(buffer: &[u8]) -> io::Result<Self> {
+        let mut search_from = 0;
+        while let Some(pos) = find(&buffer[search_from..], MARKER_FILENAME) {
+            let start = search_from + pos;
+            if let Some(container) = Self::parse_at(buffer, start) {
+                return Ok(container);
+            }
+            search_from = start + 1;
+        }
+
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Not a packed executable (payload markers not found)"))
+    }
+
+    fn parse_at(buffer: &[u8], start: usize) -> Option<Self> {
+        let mut pos = start + MARKER_FILENAME.len();
+        let name = decode_name(take_line(buffer, &mut pos)?)?;
+
+        let cleanup = buffer[pos..].starts_with(MARKER_CLEANUP);
+        if cleanup {
+            pos += MARKER_CLEANUP.len();
+        }
+
+        let mut metadata = BTreeMap::new();
+        if buffer[pos..].starts_with(MARKER_METADATA) {
+            pos += MARKER_METADATA.len();
+            let json = take_sized(buffer, &mut pos)?;
+            metadata = serde_json::from_slice(&json).ok()?;
+        }
+
+        if !buffer[pos..].starts_with(MARKER_FILE_CONTENT) {
+            return None;
+        }
+        pos += MARKER_FILE_CONTENT.len();
+        let (data, params) = take_entry(buffer, &mut pos)?;
+        let main = Entry { name, data, params };
+
+        let mut extras = vec![];
+        while pos < buffer.len() {
+            if !buffer[pos..].starts_with(MARKER_EXTRA) {
+                return None;
+            }
+            pos += MARKER_EXTRA.len();
+            let name = decode_name(take_line(buffer, &mut pos)?)?;
+            pos += 1;
+            let (data, params) = take_entry(buffer, &mut pos)?;
+            extras.push(Entry { name, data, params });
+        }
+
+        Some(Container {
+            stub: buffer[..start].to_vec(),
+            cleanup,
+            metadata,
+            main,
+            extras,
+        })
+    }
+
+    /// The extra files in the order they are decompressed: each solid block
+    /// with all its files, every other entry on its own.
+    pub fn extra_groups(&self) -> Vec<Range<usize>> {
+        let mut groups: Vec<Range<usize>> = vec![];
+        for (i, entry) in self.extras.iter().enumerate() {
+            match groups.last_mut() {
+                Some(group)
+                    if entry.solid().is_some()
+                        && entry.data.is_empty()
+                        && self.extras[group.start].solid().is_some() =>
+                {
+                    group.end = i + 1
+                }
+                _ => groups.push(i..i + 1),
+            }
+        }
+        groups
+    }
+
+    /// The original bytes of the extra files in `group`, one of [`Container::extra_groups`].
+    pub fn decompress_group(&self, group: Range<usize>) -> io::Result<Vec<Vec<u8>>> {
+        let entries = &self.extras[group];
+        let first = &entries[0];
+        if first.solid().is_none() {
+            return Ok(vec![first.decompress()?]);
+        }
+        let block = first.decode_stream()?;
+        let sizes: Vec<u64> = entries.iter().filter_map(|e| e.solid()).collect();
+        let total = sizes.iter().try_fold(0u64, |sum, &size| sum.checked_add(size));
+        if total != Some(block.len() as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Solid block starting at {} holds {} bytes, not the size of its {} files",
+                    first.name,
+                    block.len(),
+                    entries.len()
+                ),
+            ));
+        }
+        let mut rest = &block[..];
+        let mut files = Vec::with_capacity(entries.len());
+        for (entry, size) in entries.iter().zip(sizes) {
+            let (data, tail) = rest.split_at(size as usize);
+            let mut data = data.to_vec();
+            entry.unfilter(&mut data)?;
+            files.push(data);
+            rest = tail;
+        }
+        Ok(files)
+    }
+
+    /// Serializes the container in the layout the stub loader expects.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Writing to a Vec cannot fail
+        let mut writer = Writer::new(vec![], &self.stub, &self.main.name, self.cleanup, &self.metadata).unwrap();
+        writer.main_entry(&self.main.params, self.main.data.len() as u64, &mut &self.main.data[..]).unwrap();
+        for entry in &self.extras {
+            writer.extra_entry(&entry.name, &entry.params, entry.data.len() as u64, &mut &entry.data[..]).unwrap();
+        }
+        writer.finish().unwrap().0
+    }
+}
+
+/// Writes a container front to back, copying each payload from a reader so
+/// none of it has to be held in memory. The main entry must be written
+/// before any extra entry.
+pub struct Writer<W: Write> {
+    out: W,
+    written: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the stub and everything up to the main entry.
+    pub fn new(out: W, stub: &[u8], main_name: &str, cleanup: bool, metadata: &BTreeMap<String, String>) -> io::Result<Self> {
+        let mut writer = Writer { out, written: 0 };
+        writer.put(stub)?;
+        writer.put(MARKER_FILENAME)?;
+        writer.put(STANDARD.encode(main_name.as_bytes()).as_bytes())?;
+
+        if cleanup {
+            writer.put(MARKER_CLEANUP)?;
+        }
+
+        if !metadata.is_empty() {
+            let json = serde_json::to_vec(metadata).unwrap();
+            writer.put(MARKER_METADATA)?;
+            writer.put(format!("{}\n", json.len()).as_bytes())?;
+            writer.put(&json)?;
+        }
+        Ok(writer)
+    }
+
+    pub fn main_entry(&mut self, params: &BTreeMap<String, String>, len: u64, data: &mut dyn Read) -> io::Result<()> {
+        self.put(MARKER_FILE_CONTENT)?;
+        self.entry(params, len, data)
+    }
+
+    pub fn extra_entry(&mut self, name: &str, params: &BTreeMap<String, String>, len: u64, data: &mut dyn Read) -> io::Result<()> {
+        self.put(MARKER_EXTRA)?;
+        self.put(STANDARD.encode(name.as_bytes()).as_bytes())?;
+        self.put(b"\n")?;
+        self.entry(params, len, data)
+    }
+
+    /// Bytes written so far, which is where the next entry starts.
+    pub fn position(&self) -> u64 {
+        self.written
+    }
+
+    /// Flushes the output and returns it with the number of bytes written.
+    pub fn finish(mut self) -> io::Result<(W, u64)> {
+        self.out.flush()?;
+        Ok((self.out, self.written))
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Writes a `<len>[ key=value...]\n<bytes>` entry. `data` must hold
+    /// exactly `len` bytes.
+    fn entry(&mut self, params: &BTreeMap<String, String>, len: u64, data: &mut dyn Read) -> io::Result<()> {
+        let mut header = len.to_string();
+        for (key, value) in params {
+            header.push_str(&format!(" {}={}", key, value));
+        }
+        header.push('\n');
+        self.put(header.as_bytes())?;
+
+        let copied = io::copy(&mut data.take(len), &mut self.out)?;
+        self.written += copied;
+        if copied != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Entry data ended after {} of {} bytes", copied, len),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Returns the bytes up to (not including) the next newline and leaves `pos` on it.
+fn take_line<'a>(buffer: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = buffer[*pos..].iter().position(|&b| b == b'\n')?;
+    let line = &buffer[*pos..*pos + len];
+    *pos += len;
+    Some(line)
+}
+
+/// Reads a `<len>\n<bytes>` block.
+fn take_sized(buffer: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
+    let len_line = take_line(buffer, pos)?;
+    let len = std::str::from_utf8(len_line).ok()?.trim().parse::<usize>().ok()?;
+    *pos += 1;
+    let end = pos.checked_add(len)?;
+    if end > buffer.len() {
+        return None;
+    }
+    let data = buffer[*pos..end].to_vec();
+    *pos = end;
+    Some(data)
+}
+
+/// Reads a `<len>[ key=value...]\n<bytes>` entry.
+fn take_entry(buffer: &[u8], pos: &mut usize) -> Option<(Vec<u8>, BTreeMap<String, String>)> {
+    let header = std::str::from_utf8(take_line(buffer, pos)?).ok()?;
+    let mut fields = header.split_whitespace();
+    let len = fields.next()?.parse::<usize>().ok()?;
+    let mut params = BTreeMap::new();
+    for field in fields {
+        let (key, value) = field.split_once('=')?;
+        params.insert(key.to_string(), value.to_string());
+    }
+
+    *pos += 1;
+    let end = pos.checked_add(len)?;
+    if end > buffer.len() {
+        return None;
+    }
+    let data = buffer[*pos..end].to_vec();
+    *pos = end;
+    Some((data, params))
+}
+
+/// Entry header param for a symlink to `target`; base64 keeps it one word.
+pub fn link_param(target: &str) -> String {
+    STANDARD.encode(target.as_bytes())
+}
+
+/// The symlink target stored by `link_param`.
+pub fn link_target(param: &str) -> Option<String> {
+    decode_name(param.as_bytes())
+}
+
+fn decode_name(encoded: &[u8]) -> Option<String> {
+    let decoded = STANDARD.decode(encoded).ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn sample() -> Container {
+        // The stub holds the marker strings too, as the real stub does
+        let mut stub = b"MZ stub".to_vec();
+        stub.extend_from_slice(MARKER_FILENAME);
+        stub.extend_from_slice(b"not a name\n");
+        stub.extend_from_slice(MARKER_EXTRA);
+        let mut extras = vec![
+            Entry::new("assets/data.bin".to_string(), vec![0, 1, 2, b'\n', 255]),
+            Entry::new("empty file".to_string(), vec![]),
+            Entry::new("lib/current".to_string(), vec![]),
+        ];
+        extras[0].params = params(&[("codec", "2"), ("mode", "644")]);
+        extras[2].params = params(&[("link", &link_param("../lib/v1"))]);
+        let mut main = Entry::new("app.exe".to_string(), b"compressed\n--EXTRA-FILE--\nbytes".to_vec());
+        main.params = params(&[("codec", "1"), ("filter", "xz-x86")]);
+        Container {
+            stub,
+            cleanup: true,
+            metadata: params(&[("codec", "lzma"), ("level", "9")]),
+            main,
+            extras,
+        }
+    }
+
+    fn assert_same_entry(a: &Entry, b: &Entry) {
+        assert_eq!((&a.name, &a.data, &a.params), (&b.name, &b.data, &b.params));
+    }
+
+    #[test]
+    fn round_trip() {
+        let container = sample();
+        let parsed = Container::parse(&container.to_bytes()).unwrap();
+        assert_eq!(parsed.stub, container.stub);
+        assert_eq!(parsed.cleanup, container.cleanup);
+        assert_eq!(parsed.metadata, container.metadata);
+        assert_same_entry(&parsed.main, &container.main);
+        assert_eq!(parsed.extras.len(), container.extras.len());
+        for (a, b) in parsed.extras.iter().zip(&container.extras) {
+            assert_same_entry(a, b);
+        }
+        assert_eq!(parsed.extras[2].link().as_deref(), Some("../lib/v1"));
+        assert_eq!(parsed.extras[0].mode(), Some(0o644));
+    }
+
+    #[test]
+    fn round_trip_without_cleanup_or_metadata() {
+        let mut container = sample();
+        container.cleanup = false;
+        container.metadata.clear();
+        container.extras.clear();
+        let parsed = Container::parse(&container.to_bytes()).unwrap();
+        assert!(!parsed.cleanup);
+        assert!(parsed.metadata.is_empty());
+        assert!(parsed.extras.is_empty());
+        assert_same_entry(&parsed.main, &container.main);
+    }
+
+    #[test]
+    fn writer_matches_to_bytes() {
+        let container = sample();
+        let mut writer = Writer::new(vec![], &container.stub, &container.main.name, true, &container.metadata).unwrap();
+        let main = &container.main;
+        writer.main_entry(&main.params, main.data.len() as u64, &mut &main.data[..]).unwrap();
+        for entry in &container.extras {
+            writer.extra_entry(&entry.name, &entry.params, entry.data.len() as u64, &mut &entry.data[..]).unwrap();
+        }
+        let position = writer.position();
+        let (bytes, written) = writer.finish().unwrap();
+        assert_eq!(bytes, container.to_bytes());
+        assert_eq!((position, written), (bytes.len() as u64, bytes.len() as u64));
+    }
+
+    #[test]
+    fn writer_rejects_short_data() {
+        let mut writer = Writer::new(vec![], b"stub", "app", false, &BTreeMap::new()).unwrap();
+        let e = writer.main_entry(&BTreeMap::new(), 10, &mut &b"short"[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Extras with a solid block of `files` between two separate entries.
+    fn with_solid_block(files: &[(&str, &[u8], Option<Filter>)], sizes: &[u64]) -> Container {
+        let mut stream = vec![];
+        let mut members = vec![];
+        for ((name, data, filter), size) in files.iter().zip(sizes) {
+            let mut filtered = vec![];
+            let params = crate::codec::stage_solid(*filter, &mut &data[..], &mut filtered).unwrap();
+            stream.extend_from_slice(&filtered);
+            let mut entry = Entry::new(name.to_string(), vec![]);
+            entry.params = params;
+            entry.params.insert("solid".to_string(), size.to_string());
+            entry.params.insert("codec".to_string(), codecs::by_name("zstd").unwrap().id().to_string());
+            members.push(entry);
+        }
+        let zstd = codecs::by_name("zstd").unwrap();
+        let tuning = codecs::Tuning::default();
+        members[0].data = crate::codec::compress_entry(String::new(), zstd, 3, &tuning, 1, None, &stream).unwrap().data;
+        let mut container = sample();
+        container.extras.truncate(2);
+        container.extras.extend(members);
+        container.extras.push(Entry::new("after".to_string(), vec![]));
+        container.extras[5].params = params(&[("link", &link_param("a"))]);
+        container
+    }
+
+    #[test]
+    fn solid_block_round_trip() {
+        let code: Vec<u8> = (0..4096u32).flat_map(|i| [0xE8, i as u8, 0, 0, 0, 0x90]).collect();
+        let files: [(&str, &[u8], Option<Filter>); 3] =
+            [("a.txt", b"first file\n", None), ("empty", b"", None), ("tool", &code, Some(Filter::X86))];
+        let sizes: Vec<u64> = files.iter().map(|f| f.1.len() as u64).collect();
+        let container = Container::parse(&with_solid_block(&files, &sizes).to_bytes()).unwrap();
+
+        let groups = container.extra_groups();
+        assert_eq!(groups, vec![0..1, 1..2, 2..5, 5..6]);
+        let contents = container.decompress_group(2..5).unwrap();
+        assert_eq!(contents, files.iter().map(|f| f.1.to_vec()).collect::<Vec<_>>());
+        assert!(container.extras[2].decompress().is_err());
+
+        let mut wrong = sizes.clone();
+        wrong[0] += 1;
+        let container = with_solid_block(&files, &wrong);
+        assert!(container.decompress_group(2..5).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_or_trailing_bytes() {
+        let bytes = sample().to_bytes();
+        assert!(Container::parse(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.extend_from_slice(b"junk");
+        assert!(Container::parse(&trailing).is_err());
+        assert!(Container::parse(b"MZ no payload here").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+// What can go wrong in a pack, by the part that failed. Each kind exits with
+// its own code, listed in the README, so scripts can tell them apart.
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub enum PackError {
+    /// Any other failure, such as in the commands other than pack
+    Other(String),
+    /// Invalid settings on the command line, in the config file or in a profile
+    Settings(String),
+    /// The input executable cannot be read
+    Input { path: PathBuf, message: String },
+    /// An extra file, directory, list or archive cannot be read
+    Extra { path: PathBuf, message: String },
+    /// Compressing a file failed, other than by reading it
+    Codec { path: PathBuf, message: String },
+    /// cargo could not build the stub loader
+    Stub { path: PathBuf, message: String },
+    /// The icon, version info or manifest settings could not be written for the stub build
+    Resource { path: PathBuf, message: String },
+    /// The output or the report could not be written
+    Output { path: PathBuf, message: String },
+    /// The output is over --max-output-size or failed --check-reproducible
+    Check { path: PathBuf, message: String },
+    /// Several files that failed while being compressed in parallel
+    Many(Vec<PackError>),
+    /// Entries of a batch failed; each was reported as it was summarized
+    Batch { failed: usize, total: usize, exit_code: i32 },
+}
+
+/// `e` as text, without the leading `path: ` that many messages already have.
+fn message(path: &Path, e: impl fmt::Display) -> String {
+    let message = e.to_string();
+    match message.strip_prefix(&format!("{}: ", path.display())) {
+        Some(rest) => rest.to_string(),
+        None => message,
+    }
+}
+
+impl PackError {
+    pub fn input(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Input {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    pub fn extra(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Extra {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    pub fn codec(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Codec {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    pub fn stub(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Stub {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    pub fn resource(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Resource {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    pub fn output(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Output {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    pub fn check(path: &Path, e: impl fmt::Display) -> PackError {
+        PackError::Check {
+            path: path.to_path_buf(),
+            message: message(path, e),
+        }
+    }
+
+    /// An error compressing the file at `path`, read through `Reading`: a
+    /// failed read is the file's fault, made by `unreadable`, and anything
+    /// else the codec's.
+    pub fn compressing(path: &Path, e: io::Error, unreadable: fn(&Path, io::Error) -> PackError) -> PackError {
+        if e.get_ref().is_some_and(|inner| inner.is::<ReadError>()) {
+            unreadable(path, e)
+        } else {
+            PackError::codec(path, e)
+        }
+    }
+
+    /// The failures of files compressed in parallel, as one error.
+    pub fn many(mut errors: Vec<PackError>) -> PackError {
+        if errors.len() == 1 {
+            errors.pop().unwrap()
+        } else {
+            PackError::Many(errors)
+        }
+    }
+
+    /// The process exit code for this kind of error. Several errors exit
+    /// with the code of the first.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PackError::Other(_) => 1,
+            // 2 is taken by clap for command line usage errors
+            PackError::Settings(_) => 3,
+            PackError::Input { .. } => 4,
+            PackError::Extra { .. } => 5,
+            PackError::Codec { .. } => 6,
+            PackError::Stub { .. } => 7,
+            PackError::Resource { .. } => 8,
+            PackError::Output { .. } => 9,
+            PackError::Check { .. } => 10,
+            PackError::Many(errors) => errors.first().map_or(1, PackError::exit_code),
+            PackError::Batch { exit_code, .. } => *exit_code,
+        }
+    }
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackError::Other(message) | PackError::Settings(message) => write!(f, "{}", message),
+            PackError::Input { path, message } => write!(f, "input {}: {}", path.display(), message),
+            PackError::Extra { path, message } => write!(f, "extra file {}: {}", path.display(), message),
+            PackError::Codec { path, message } => write!(f, "compressing {}: {}", path.display(), message),
+            PackError::Stub { path, message } => write!(f, "building the stub in {}: {}", path.display(), message),
+            PackError::Resource { path, message } => write!(f, "stub resource {}: {}", path.display(), message),
+            PackError::Output { path, message } | PackError::Check { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+            PackError::Batch { failed, total, .. } => write!(f, "{} of {} batch entries failed", failed, total),
+            PackError::Many(errors) => {
+                write!(f, "{} files failed:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for PackError {}
+
+impl From<String> for PackError {
+    fn from(message: String) -> PackError {
+        PackError::Settings(message)
+    }
+}
+
+/// Carries the error through code that returns `io::Result`.
+impl From<PackError> for io::Error {
+    fn from(e: PackError) -> io::Error {
+        io::Error::other(e)
+    }
+}
+
+/// Gets back a `PackError` carried in an `io::Error`; other I/O errors
+/// become `Other`.
+impl From<io::Error> for PackError {
+    fn from(e: io::Error) -> PackError {
+        if e.get_ref().is_some_and(|inner| inner.is::<PackError>()) {
+            *e.into_inner().unwrap().downcast::<PackError>().unwrap()
+        } else {
+            PackError::Other(e.to_string())
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ReadError(io::Error);
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for ReadError {}
+
+/// Passes reads through, marking their errors so `PackError::compressing`
+/// can tell them from codec errors.
+pub struct Reading<R>(pub R);
+
+impl<R: Read> Read for Reading<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| io::Error::new(e.kind(), ReadError(e)))
+    }
+}
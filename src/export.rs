@@ -0,0 +1,295 @@
+// `export` turns a packed executable back into a standard archive, for audits
+// and for machines where self-extracting executables are blocked. Entries are
+// decompressed in memory and written straight into the archive.
+use crate::container::{Container, Entry};
+use crate::patch::sha256_hex;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tar::{EntryType, Header};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// JSON file describing the packed executable, written at the root of the archive
+pub const METADATA_FILE: &str = "execompress-metadata.json";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl Format {
+    /// Format named by the output's extension.
+    fn from_path(path: &Path) -> Option<Format> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        [
+            (".zip", Format::Zip),
+            (".tar", Format::Tar),
+            (".tar.gz", Format::TarGz),
+            (".tgz", Format::TarGz),
+            (".tar.zst", Format::TarZst),
+            (".tzst", Format::TarZst),
+        ]
+        .into_iter()
+        .find(|(extension, _)| name.ends_with(extension))
+        .map(|(_, format)| format)
+    }
+}
+
+/// The stream under a tar archive.
+enum Stream<'a> {
+    Plain(BufWriter<&'a File>),
+    Gz(GzEncoder<BufWriter<&'a File>>),
+    Zst(zstd::Encoder<'static, BufWriter<&'a File>>),
+}
+
+impl Write for Stream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(w) => w.write(buf),
+            Stream::Gz(w) => w.write(buf),
+            Stream::Zst(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(w) => w.flush(),
+            Stream::Gz(w) => w.flush(),
+            Stream::Zst(w) => w.flush(),
+        }
+    }
+}
+
+impl Stream<'_> {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Stream::Plain(mut w) => w.flush(),
+            Stream::Gz(w) => w.finish()?.flush(),
+            Stream::Zst(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+/// Entries are stamped with the packed executable's modification time.
+enum Bundle<'a> {
+    Tar(tar::Builder<Stream<'a>>, u64),
+    Zip(Box<ZipWriter<BufWriter<&'a File>>>, SimpleFileOptions),
+}
+
+/// Zip timestamp for `secs` since the Unix epoch, in UTC. Zip dates start in
+/// 1980, so earlier times are clamped to its start.
+fn zip_time(secs: u64) -> zip::DateTime {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    zip::DateTime::from_date_and_time(
+        year.clamp(1980, 2107) as u16,
+        month,
+        day,
+        (time / 3600) as u8,
+        (time % 3600 / 60) as u8,
+        (time % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+impl<'a> Bundle<'a> {
+    fn new(format: Format, file: &'a File, mtime: u64) -> io::Result<Bundle<'a>> {
+        let out = BufWriter::new(file);
+        let stream = match format {
+            Format::Zip => {
+                let options = SimpleFileOptions::default().last_modified_time(zip_time(mtime));
+                return Ok(Bundle::Zip(Box::new(ZipWriter::new(out)), options));
+            }
+            Format::Tar => Stream::Plain(out),
+            Format::TarGz => Stream::Gz(GzEncoder::new(out, Compression::default())),
+            Format::TarZst => Stream::Zst(zstd::Encoder::new(out, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        };
+        Ok(Bundle::Tar(tar::Builder::new(stream), mtime))
+    }
+
+    fn file(&mut self, name: &str, mode: u32, data: &[u8]) -> io::Result<()> {
+        match self {
+            Bundle::Tar(builder, mtime) => {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(data.len() as u64);
+                header.set_mode(mode);
+                header.set_mtime(*mtime);
+                builder.append_data(&mut header, name, data)
+            }
+            Bundle::Zip(zip, options) => {
+                let options = options
+                    .compression_method(CompressionMethod::Deflated)
+                    .unix_permissions(mode)
+                    .large_file(data.len() as u64 >= u32::MAX as u64);
+                zip.start_file(name, options)?;
+                zip.write_all(data)
+            }
+        }
+    }
+
+    fn symlink(&mut self, name: &str, target: &str) -> io::Result<()> {
+        match self {
+            Bundle::Tar(builder, mtime) => {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                header.set_mode(0o777);
+                header.set_mtime(*mtime);
+                builder.append_link(&mut header, name, target)
+            }
+            Bundle::Zip(zip, options) => Ok(zip.add_symlink(name, target, *options)?),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Bundle::Tar(builder, _) => builder.into_inner()?.finish(),
+            Bundle::Zip(zip, _) => zip.finish()?.flush(),
+        }
+    }
+}
+
+fn decompress(entry: &Entry) -> io::Result<Vec<u8>> {
+    entry
+        .decompress()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to decompress {}: {}", entry.name, e)))
+}
+
+/// How an entry was stored, for the metadata file.
+fn describe(entry: &Entry, data: &[u8]) -> Value {
+    let mut value = json!({
+        "name": entry.name,
+        "codec": entry.codec(),
+        "compressed": entry.data.len(),
+    });
+    if let Some(target) = entry.link() {
+        value["link"] = json!(target);
+        return value;
+    }
+    value["original"] = json!(data.len());
+    value["sha256"] = json!(sha256_hex(data));
+    if let Some(filter) = entry.filter() {
+        value["filter"] = json!(filter);
+    }
+    if let Some(level) = entry.params.get("level") {
+        value["level"] = json!(level);
+    }
+    if entry.solid().is_some() {
+        value["solid"] = json!(true);
+    }
+    if let Some(mode) = entry.mode() {
+        value["mode"] = json!(format!("{:o}", mode));
+    }
+    value
+}
+
+/// Writes the main executable and extra files of packed executable `input`
+/// to a zip, tar, tar.gz or tar.zst archive, chosen by `output`'s extension,
+/// along with a JSON description of the container.
+pub fn export(input: &Path, output: &Path) -> io::Result<()> {
+    let format = Format::from_path(output).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot tell the archive format of {:?}: use a .zip, .tar, .tar.gz or .tar.zst name", output),
+        )
+    })?;
+    let bytes = fs::read(input)?;
+    let container = Container::parse(&bytes)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", input.display(), e)))?;
+    if container.main.name == METADATA_FILE || container.extras.iter().any(|e| e.name == METADATA_FILE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} already has an entry named {}", input.display(), METADATA_FILE),
+        ));
+    }
+    let mtime = fs::metadata(input)?
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs());
+
+    // Written under a temporary name next to the output, like a packed executable
+    let output_dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut partial_file = tempfile::Builder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        partial_file.permissions(fs::Permissions::from_mode(0o666));
+    }
+    let file = partial_file.tempfile_in(output_dir)?;
+    let mut bundle = Bundle::new(format, file.as_file(), mtime)?;
+
+    let main = decompress(&container.main)?;
+    bundle.file(&container.main.name, 0o755, &main)?;
+    let mut entries = vec![describe(&container.main, &main)];
+    drop(main);
+    // A batch of extras (or solid blocks) at a time, so only a few are held decompressed
+    let groups = container.extra_groups();
+    for batch in groups.chunks(rayon::current_num_threads()) {
+        let data = batch
+            .par_iter()
+            .map(|group| {
+                container.decompress_group(group.clone()).map_err(|e| {
+                    let name = &container.extras[group.start].name;
+                    io::Error::new(e.kind(), format!("Failed to decompress {}: {}", name, e))
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let members = batch.iter().flat_map(|group| &container.extras[group.clone()]);
+        for (entry, data) in members.zip(data.into_iter().flatten()) {
+            match entry.link() {
+                Some(target) => bundle.symlink(&entry.name, &target)?,
+                None => bundle.file(&entry.name, entry.mode().unwrap_or(0o644), &data)?,
+            }
+            entries.push(describe(entry, &data));
+        }
+    }
+
+    let metadata = json!({
+        "packed": input.display().to_string(),
+        "packed_size": bytes.len(),
+        "packed_sha256": sha256_hex(&bytes),
+        "stub_size": container.stub.len(),
+        "main": container.main.name,
+        "cleanup": container.cleanup,
+        "metadata": container.metadata,
+        "entries": entries,
+    });
+    bundle.file(METADATA_FILE, 0o644, serde_json::to_string_pretty(&metadata).unwrap().as_bytes())?;
+    bundle.finish()?;
+    let size = file.as_file().metadata()?.len();
+    file.persist(output).map_err(|e| e.error)?;
+    info!(
+        "Exported {} entries and {} from {:?} to {:?} ({} bytes)",
+        container.extras.len() + 1,
+        METADATA_FILE,
+        input,
+        output,
+        size
+    );
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use crate::archive::{Archive, Kind};
+use crate::config::Mapping;
+use crate::error::PackError;
+use crate::rules::Choice;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+/// Read from the root of each extra directory, in gitignore syntax
+pub const IGNORE_FILE: &str = ".execompressignore";
+
+/// An extra file to pack: where it goes in the bundle and where it is read from.
+#[derive(Clone)]
+pub struct ExtraFile {
+    /// Path inside the bundle, with `/` separators
+    pub name: String,
+    pub source: Source,
+    /// Size before compression
+    pub size: u64,
+    /// Unix permission bits, kept for files from archives
+    pub mode: Option<u32>,
+    /// Target of a symlink from an archive; symlinks have no data
+    pub link: Option<String>,
+    /// Codec from the first matching --rule; the global settings otherwise
+    pub rule: Option<Choice>,
+}
+
+/// Where an extra file's data is read from.
+#[derive(Clone)]
+pub enum Source {
+    File(PathBuf),
+    /// A member of an archive added with --add-archive
+    Member {
+        archive: Arc<Archive>,
+        index: usize,
+        path: String,
+    },
+}
+
+impl Source {
+    /// The file, or `archive:member` for an archive member.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            Source::File(path) => path.clone(),
+            Source::Member { .. } => PathBuf::from(self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Member { archive, path, .. } => write!(f, "{}:{}", archive.path.display(), path),
+        }
+    }
+}
+
+impl ExtraFile {
+    fn new(name: String, source: Source, size: u64) -> ExtraFile {
+        ExtraFile {
+            name,
+            source,
+            size,
+            mode: None,
+            link: None,
+            rule: None,
+        }
+    }
+}
+
+/// `rel_path` under the bundle directory `prefix`.
+fn join(prefix: &str, rel_path: String) -> String {
+    if prefix.is_empty() {
+        rel_path
+    } else {
+        format!("{}/{}", prefix, rel_path)
+    }
+}
+
+/// Parses an `--add` or `--files-from` entry, `<src>[:<dest>]`, with `src`
+/// relative to `base`. A colon after a single letter is part of a drive
+/// letter, not the start of `dest`.
+pub fn parse_mapping(spec: &str, base: &Path) -> Result<Mapping, String> {
+    let (source, dest) = match spec.rsplit_once(':') {
+        Some((source, dest)) if source.len() != 1 => (source, Some(dest.to_string())),
+        _ => (spec, None),
+    };
+    if source.is_empty() {
+        return Err("expected <src>[:<dest>]".to_string());
+    }
+    let mapping = Mapping {
+        source: base.join(source),
+        dest,
+    };
+    mapping.check_dest()?;
+    Ok(mapping)
+}
+
+/// Reads a `--files-from` list: one `<src>[:<dest>]` per line, blank lines
+/// and `#` comments skipped. Sources are relative to the list's directory,
+/// or to the current directory when the list is read from stdin (`-`).
+pub fn read_list(path: &Path) -> io::Result<Vec<Mapping>> {
+    let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let (text, base) = if path == Path::new("-") {
+        (io::read_to_string(io::stdin()).map_err(context)?, Path::new(""))
+    } else {
+        (fs::read_to_string(path).map_err(context)?, path.parent().unwrap_or(Path::new("")))
+    };
+
+    let mut mappings = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mapping = parse_mapping(line, base).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), i + 1, e))
+        })?;
+        mappings.push(mapping);
+    }
+    Ok(mappings)
+}
+
+/// `--include`/`--exclude` globs, matched against the path inside the bundle.
+/// A glob also matches everything under a directory it matches.
+#[derive(Default)]
+pub struct Selection {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+fn glob_set(globs: &[String], flag: &str) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).map_err(|e| format!("Invalid {} '{}': {}", flag, glob, e))?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+impl Selection {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Selection, String> {
+        Ok(Selection {
+            include: if include.is_empty() { None } else { Some(glob_set(include, "--include")?) },
+            exclude: glob_set(exclude, "--exclude")?,
+        })
+    }
+
+    /// Whether the file at `name` is packed: it matches an include glob, if
+    /// any were given, and no exclude glob.
+    fn selects(&self, name: &str) -> bool {
+        let matches = |set: &GlobSet| {
+            name.match_indices('/')
+                .map(|(i, _)| &name[..i])
+                .chain([name])
+                .any(|path| set.is_match(path))
+        };
+        self.include.as_ref().is_none_or(matches) && !matches(&self.exclude)
+    }
+}
+
+/// The ignore file at the root of `dir`, or an empty one.
+fn ignore_file(dir: &Path) -> io::Result<Gitignore> {
+    let path = dir.join(IGNORE_FILE);
+    if !path.is_file() {
+        return Ok(Gitignore::empty());
+    }
+    let invalid = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&path) {
+        return Err(invalid(e));
+    }
+    builder.build().map_err(invalid)
+}
+
+/// Every selected file under `dir`, named by its path relative to `dir`
+/// under `prefix`. Ignored directories are not descended into.
+fn walk(dir: &Path, prefix: &str, selection: &Selection, files: &mut Vec<ExtraFile>) -> io::Result<()> {
+    let ignore = ignore_file(dir)?;
+    let walker = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !ignore.matched(e.path(), e.file_type().is_dir()).is_ignore());
+    for entry in walker.filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+        if entry.depth() == 1 && entry.file_name() == IGNORE_FILE {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(dir).unwrap().to_string_lossy().replace("\\", "/");
+        let name = join(prefix, rel_path);
+        if selection.selects(&name) {
+            let size = entry.metadata().map_err(io::Error::from)?.len();
+            files.push(ExtraFile::new(name, Source::File(entry.into_path()), size));
+        }
+    }
+    Ok(())
+}
+
+/// Every selected file and symlink in the archive at `source`, under `prefix`.
+fn list_archive(source: &Path, prefix: &str, selection: &Selection, files: &mut Vec<ExtraFile>) -> io::Result<()> {
+    let archive = Arc::new(Archive::open(source)?);
+    for member in archive.members()? {
+        let name = join(prefix, member.path.clone());
+        if !selection.selects(&name) {
+            continue;
+        }
+        let source = Source::Member {
+            archive: archive.clone(),
+            index: member.index,
+            path: member.path,
+        };
+        let mut file = ExtraFile::new(name, source, member.size);
+        file.mode = member.mode;
+        if let Kind::Symlink(target) = member.kind {
+            file.link = Some(target);
+        }
+        files.push(file);
+    }
+    Ok(())
+}
+
+/// Gathers the files of the extra directory, the mapped files and
+/// directories and the archive members, sorted by name so the output does
+/// not depend on the order the file system lists them in. `selection`
+/// applies to directories and archives, and the ignore files to
+/// directories; a file mapped on its own is always packed. An archive's
+/// members land at the root of the bundle unless it has a destination.
+pub fn collect(
+    extra_dir: Option<&Path>,
+    map: &[Mapping],
+    archives: &[Mapping],
+    selection: &Selection,
+) -> Result<Vec<ExtraFile>, PackError> {
+    let mut files = vec![];
+    if let Some(dir) = extra_dir.filter(|d| d.is_dir()) {
+        walk(dir, "", selection, &mut files).map_err(|e| PackError::extra(dir, e))?;
+    }
+
+    for mapping in map {
+        let source = &mapping.source;
+        if source.is_dir() {
+            walk(source, &mapping.dest(), selection, &mut files).map_err(|e| PackError::extra(source, e))?;
+        } else if source.is_file() {
+            let size = fs::metadata(source).map_err(|e| PackError::extra(source, e))?.len();
+            files.push(ExtraFile::new(mapping.dest(), Source::File(source.clone()), size));
+        } else {
+            return Err(PackError::extra(source, "no such file or directory"));
+        }
+    }
+
+    for mapping in archives {
+        let prefix = if mapping.dest.is_some() { mapping.dest() } else { String::new() };
+        list_archive(&mapping.source, &prefix, selection, &mut files).map_err(|e| PackError::extra(&mapping.source, e))?;
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/// Runs `f` on each file with a reader for its data, in parallel on the
+/// current rayon pool, and returns the results in the order of `files`.
+/// Tar members can only be read in order, so each tar archive is read by
+/// one thread in a single pass while the other files are read alongside.
+/// A tar member packed twice (a hard link) is read again for its copy.
+/// After a failure no more files are started, and the failures of those
+/// already running are returned together.
+pub fn read_all<T, F>(files: &[ExtraFile], f: F) -> Result<Vec<T>, PackError>
+where
+    T: Send,
+    F: Fn(&ExtraFile, &mut dyn Read) -> Result<T, PackError> + Sync,
+{
+    let mut single = vec![];
+    // Per sequential archive: member index to position in `files`
+    let mut sequential: Vec<(&Arc<Archive>, HashMap<usize, usize>)> = vec![];
+    for (i, file) in files.iter().enumerate() {
+        match &file.source {
+            Source::Member { archive, index, .. } if archive.is_sequential() => {
+                match sequential.iter_mut().find(|(a, _)| Arc::ptr_eq(a, archive)) {
+                    Some((_, members)) if members.contains_key(index) => single.push(i),
+                    Some((_, members)) => {
+                        members.insert(*index, i);
+                    }
+                    None => sequential.push((archive, HashMap::from([(*index, i)]))),
+                }
+            }
+            _ => single.push(i),
+        }
+    }
+
+    let failed = AtomicBool::new(false);
+    // Errors of `f` pass through the archive reader inside an io::Error;
+    // any other error is the archive's own
+    let archive_error = |path: &Path, e: io::Error| match PackError::from(e) {
+        PackError::Other(message) => PackError::extra(path, message),
+        e => e,
+    };
+    let read_archive = |(archive, members): &(&Arc<Archive>, HashMap<usize, usize>)| {
+        let mut results = vec![];
+        archive
+            .for_each(|index, input| {
+                match members.get(&index) {
+                    Some(&i) if !failed.load(Ordering::Relaxed) => results.push((i, f(&files[i], input)?)),
+                    _ => {}
+                }
+                Ok(())
+            })
+            .map_err(|e| archive_error(&archive.path, e))?;
+        Ok(results)
+    };
+    let read_one = |i: usize| {
+        let file = &files[i];
+        if failed.load(Ordering::Relaxed) {
+            return Ok(vec![]);
+        }
+        let result = match &file.source {
+            Source::File(path) => {
+                let input = File::open(path).map_err(|e| PackError::extra(path, e))?;
+                f(file, &mut BufReader::new(input))?
+            }
+            Source::Member { archive, index, .. } => archive
+                .read(*index, |input| Ok(f(file, input)?))
+                .map_err(|e| archive_error(&file.source.path(), e))?,
+        };
+        Ok(vec![(i, result)])
+    };
+    // The archives go first, as each is one long job
+    let outcomes: Vec<Result<Vec<(usize, T)>, PackError>> = sequential
+        .par_iter()
+        .map(read_archive)
+        .chain(single.into_par_iter().map(read_one))
+        .inspect(|outcome| {
+            if outcome.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+        })
+        .collect();
+    let mut results = vec![];
+    let mut errors = vec![];
+    for outcome in outcomes {
+        match outcome {
+            Ok(done) => results.extend(done),
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(PackError::many(errors));
+    }
+    results.sort_by_key(|(i, _)| *i);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Finds extra files that would overwrite each other, or the main executable
+/// `main` (its name in the bundle and its source), when extracted: two files
+/// at the same path, or a file at a path another file needs as a directory.
+pub fn check_conflicts(files: &[ExtraFile], main: Option<(&str, &Path)>) -> Result<(), String> {
+    let main = main.map(|(name, path)| (name, path.display().to_string()));
+    let mut sources: HashMap<&str, String> = HashMap::new();
+    let mut conflicts = vec![];
+    for (name, source) in main.into_iter().chain(files.iter().map(|f| (f.name.as_str(), f.source.to_string()))) {
+        if let Some(other) = sources.get(name) {
+            conflicts.push(format!("'{}' comes from both {} and {}", name, other, source));
+        }
+        sources.insert(name, source);
+    }
+
+    // Reported once per file, however many files are under it
+    let mut blocked = BTreeSet::new();
+    for file in files {
+        for (i, _) in file.name.match_indices('/') {
+            let dir = &file.name[..i];
+            if let Some(other) = sources.get(dir) {
+                if blocked.insert(dir) {
+                    conflicts.push(format!(
+                        "'{}' is a file from {}, but {} needs it to be a directory",
+                        dir, other, file.source
+                    ));
+                }
+            }
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Conflicting destinations in the bundle:\n  {}", conflicts.join("\n  ")))
+    }
+}
@@ -0,0 +1,344 @@
+// Branch/call/jump (BCJ) filters. They rewrite relative branch targets in
+// machine code as absolute addresses, which repeat far more often and so
+// compress better. Both transforms follow the xz "simple" filters.
+use std::io::{self, Chain, Cursor, Read};
+
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+
+// Bytes read from the source per filter pass when streaming
+const STREAM_CHUNK: usize = 1024 * 1024;
+
+// Leading bytes of a file read by `detect_stream`; enough for the PE/ELF headers
+const HEADER_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    X86,
+    Arm64,
+}
+
+impl Filter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::X86 => "x86",
+            Filter::Arm64 => "arm64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "x86" => Some(Filter::X86),
+            "arm64" => Some(Filter::Arm64),
+            _ => None,
+        }
+    }
+
+    pub fn decode(&self, data: &mut [u8]) {
+        self.code(data, 0, &mut X86State::default(), false);
+    }
+
+    /// Transforms `buffer`, which starts `offset` bytes into the stream, and
+    /// returns how many leading bytes are final. The rest (a partial
+    /// instruction) must be passed again at the start of the next buffer.
+    fn code(&self, buffer: &mut [u8], offset: u32, state: &mut X86State, is_encoder: bool) -> usize {
+        match self {
+            Filter::X86 => x86_code(buffer, offset, state, is_encoder),
+            Filter::Arm64 => arm64_code(buffer, offset, is_encoder),
+        }
+    }
+}
+
+/// Applies a filter's encoder to a stream `STREAM_CHUNK` bytes at a time,
+/// giving the same output as `Filter::encode` on the whole input.
+pub struct Encoder<R> {
+    inner: R,
+    filter: Filter,
+    state: X86State,
+    buffer: Vec<u8>,
+    /// Filtered bytes at the front of `buffer`, of which `taken` are handed out
+    ready: usize,
+    taken: usize,
+    /// Stream offset of `buffer[0]`
+    offset: u32,
+    eof: bool,
+}
+
+impl<R: Read> Encoder<R> {
+    pub fn new(filter: Filter, inner: R) -> Self {
+        Encoder {
+            inner,
+            filter,
+            state: X86State::default(),
+            buffer: Vec::with_capacity(STREAM_CHUNK),
+            ready: 0,
+            taken: 0,
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    /// Drops the bytes already handed out and filters the next chunk.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.ready);
+        self.offset = self.offset.wrapping_add(self.ready as u32);
+        self.taken = 0;
+
+        while !self.eof && self.buffer.len() < STREAM_CHUNK {
+            let start = self.buffer.len();
+            self.buffer.resize(STREAM_CHUNK, 0);
+            let n = self.inner.read(&mut self.buffer[start..])?;
+            self.buffer.truncate(start + n);
+            self.eof = n == 0;
+        }
+
+        let done = self.filter.code(&mut self.buffer, self.offset, &mut self.state, true);
+        // At the end of the stream a trailing partial instruction stays as is
+        self.ready = if self.eof { self.buffer.len() } else { done };
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Encoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.taken == self.ready {
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = out.len().min(self.ready - self.taken);
+        out[..n].copy_from_slice(&self.buffer[self.taken..self.taken + n]);
+        self.taken += n;
+        Ok(n)
+    }
+}
+
+/// Picks the filter matching the machine type of a PE or ELF image.
+pub fn detect(data: &[u8]) -> Option<Filter> {
+    let machine = if data.starts_with(ELF_MAGIC) {
+        let big_endian = *data.get(5)? == 2;
+        let bytes = [*data.get(18)?, *data.get(19)?];
+        let machine = if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) };
+        match machine {
+            EM_386 | EM_X86_64 => return Some(Filter::X86),
+            EM_AARCH64 => return Some(Filter::Arm64),
+            _ => return None,
+        }
+    } else {
+        crate::pe::PeFile::parse(data)?.machine
+    };
+
+    match machine {
+        IMAGE_FILE_MACHINE_I386 | IMAGE_FILE_MACHINE_AMD64 => Some(Filter::X86),
+        IMAGE_FILE_MACHINE_ARM64 => Some(Filter::Arm64),
+        _ => None,
+    }
+}
+
+/// `input` with the bytes read by `detect_stream` put back in front.
+pub type Rewound<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// `detect` on the start of `input`. Returns a reader that yields all of
+/// `input` again, including the bytes read for detection.
+pub fn detect_stream<R: Read>(mut input: R) -> io::Result<(Option<Filter>, Rewound<R>)> {
+    let mut header = vec![];
+    (&mut input).take(HEADER_BYTES).read_to_end(&mut header)?;
+    Ok((detect(&header), Cursor::new(header).chain(input)))
+}
+
+fn test_ms_byte(b: u8) -> bool {
+    b == 0 || b == 0xFF
+}
+
+/// x86 filter state carried between buffers of one stream.
+#[derive(Clone, Copy)]
+struct X86State {
+    prev_mask: u32,
+    /// Stream offset of the last E8/E9 byte seen
+    prev_pos: u32,
+}
+
+impl Default for X86State {
+    fn default() -> Self {
+        X86State {
+            prev_mask: 0,
+            prev_pos: 0u32.wrapping_sub(5),
+        }
+    }
+}
+
+/// E8/E9 (CALL/JMP rel32) conversion, same state machine as xz's x86 filter.
+fn x86_code(buffer: &mut [u8], offset: u32, state: &mut X86State, is_encoder: bool) -> usize {
+    const MASK_TO_ALLOWED_STATUS: [bool; 8] = [true, true, true, false, true, false, false, false];
+    const MASK_TO_BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+
+    if buffer.len() < 5 {
+        return 0;
+    }
+    let X86State { mut prev_mask, mut prev_pos } = *state;
+    let limit = buffer.len() - 5;
+    let mut pos = 0usize;
+
+    while pos <= limit {
+        let b = buffer[pos];
+        if b != 0xE8 && b != 0xE9 {
+            pos += 1;
+            continue;
+        }
+
+        let stream_pos = offset.wrapping_add(pos as u32);
+        let offset = stream_pos.wrapping_sub(prev_pos);
+        prev_pos = stream_pos;
+        if offset > 5 {
+            prev_mask = 0;
+        } else {
+            for _ in 0..offset {
+                prev_mask &= 0x77;
+                prev_mask <<= 1;
+            }
+        }
+
+        let b = buffer[pos + 4];
+        if test_ms_byte(b) && MASK_TO_ALLOWED_STATUS[((prev_mask >> 1) & 0x7) as usize] && (prev_mask >> 1) < 0x10 {
+            let mut src = u32::from_le_bytes([buffer[pos + 1], buffer[pos + 2], buffer[pos + 3], b]);
+            let mut dest;
+            loop {
+                let here = stream_pos.wrapping_add(5);
+                dest = if is_encoder { src.wrapping_add(here) } else { src.wrapping_sub(here) };
+                if prev_mask == 0 {
+                    break;
+                }
+                let i = MASK_TO_BIT_NUMBER[(prev_mask >> 1) as usize];
+                let b = (dest >> (24 - i * 8)) as u8;
+                if !test_ms_byte(b) {
+                    break;
+                }
+                src = dest ^ ((1u32 << (32 - i * 8)) - 1);
+            }
+            buffer[pos + 4] = !(((dest >> 24) & 1).wrapping_sub(1)) as u8;
+            buffer[pos + 3] = (dest >> 16) as u8;
+            buffer[pos + 2] = (dest >> 8) as u8;
+            buffer[pos + 1] = dest as u8;
+            pos += 5;
+            prev_mask = 0;
+        } else {
+            pos += 1;
+            prev_mask |= 1;
+            if test_ms_byte(b) {
+                prev_mask |= 0x10;
+            }
+        }
+    }
+
+    *state = X86State { prev_mask, prev_pos };
+    pos
+}
+
+/// BL and ADRP conversion, same as xz's ARM64 filter.
+fn arm64_code(buffer: &mut [u8], offset: u32, is_encoder: bool) -> usize {
+    let mut i = 0usize;
+    while i + 4 <= buffer.len() {
+        let pc = offset.wrapping_add(i as u32);
+        let mut instr = u32::from_le_bytes([buffer[i], buffer[i + 1], buffer[i + 2], buffer[i + 3]]);
+
+        if (instr >> 26) == 0x25 {
+            // BL
+            let src = instr;
+            let pc = if is_encoder { pc >> 2 } else { 0u32.wrapping_sub(pc >> 2) };
+            instr = 0x94000000 | (src.wrapping_add(pc) & 0x03FFFFFF);
+            buffer[i..i + 4].copy_from_slice(&instr.to_le_bytes());
+        } else if (instr & 0x9F000000) == 0x90000000 {
+            // ADRP
+            let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001FFFFC);
+            if (src.wrapping_add(0x00020000) & 0x001C0000) == 0 {
+                let pc = if is_encoder { pc >> 12 } else { 0u32.wrapping_sub(pc >> 12) };
+                let dest = src.wrapping_add(pc);
+                instr &= 0x9000001F;
+                instr |= (dest & 3) << 29;
+                instr |= (dest & 0x0003FFFC) << 3;
+                instr |= 0u32.wrapping_sub(dest & 0x00020000) & 0x00E00000;
+                buffer[i..i + 4].copy_from_slice(&instr.to_le_bytes());
+            }
+        }
+        i += 4;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random bytes with x86 CALLs and ARM64 BL/ADRP instructions
+    /// mixed in, long enough to span several stream chunks.
+    fn code_like(len: usize) -> Vec<u8> {
+        let mut seed = 0x2545_f491u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            seed >> 8
+        };
+        let mut data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        for i in (0..len - 16).step_by(64) {
+            let rel = next() & 0xFFFF;
+            data[i..i + 5].copy_from_slice(&[0xE8, rel as u8, (rel >> 8) as u8, 0, 0]);
+            let bl = 0x9400_0000 | (next() & 0x03FF_FFFF);
+            data[i + 8..i + 12].copy_from_slice(&bl.to_le_bytes());
+            let adrp = 0x9000_0000 | ((next() & 0x3) << 29) | ((next() & 0x7FF) << 5);
+            data[i + 12..i + 16].copy_from_slice(&adrp.to_le_bytes());
+        }
+        data
+    }
+
+    /// Reads at most `step` bytes at a time.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.1).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn encode(filter: Filter, data: &[u8], step: usize) -> Vec<u8> {
+        let mut encoded = vec![];
+        Encoder::new(filter, Trickle(data, step)).read_to_end(&mut encoded).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = code_like(STREAM_CHUNK * 2 + 12_345);
+        for filter in [Filter::X86, Filter::Arm64] {
+            let mut encoded = encode(filter, &data, usize::MAX);
+            assert_eq!(encoded.len(), data.len());
+            assert_ne!(encoded, data, "{} changed nothing", filter.name());
+            filter.decode(&mut encoded);
+            assert_eq!(encoded, data, "{} did not round trip", filter.name());
+        }
+    }
+
+    #[test]
+    fn encoder_output_does_not_depend_on_read_sizes() {
+        let data = code_like(STREAM_CHUNK + 4_099);
+        for filter in [Filter::X86, Filter::Arm64] {
+            assert_eq!(encode(filter, &data, 4_099), encode(filter, &data, usize::MAX), "{}", filter.name());
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for filter in [Filter::X86, Filter::Arm64] {
+            assert_eq!(Filter::from_name(filter.name()), Some(filter));
+        }
+        assert_eq!(Filter::from_name("xz-x86"), None);
+    }
+}
@@ -1,3 +1,7 @@
+// Also builds standalone (see fn main below), so not every item is used by the packer
+#![allow(dead_code)]
+
+use log::{debug, trace};
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
@@ -77,7 +81,7 @@ impl IconExtractor {
     pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         let mut file = File::open(&path)?;
         
-        println!("Opening file: {}", path.as_ref().display());
+        debug!("Opening file: {}", path.as_ref().display());
         
         // Read DOS header
         let mut dos_signature = [0u8; 2];
@@ -87,7 +91,7 @@ impl IconExtractor {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a valid PE file (invalid DOS signature)"));
         }
         
-        println!("Valid DOS signature found");
+        debug!("Valid DOS signature found");
         
         // Seek to PE header offset location
         file.seek(SeekFrom::Start(0x3C))?;
@@ -95,7 +99,7 @@ impl IconExtractor {
         file.read_exact(&mut pe_offset_bytes)?;
         let pe_offset = u32::from_le_bytes(pe_offset_bytes);
         
-        println!("PE header offset: 0x{:08X}", pe_offset);
+        debug!("PE header offset: 0x{:08X}", pe_offset);
         
         // Read PE signature
         file.seek(SeekFrom::Start(pe_offset as u64))?;
@@ -106,11 +110,11 @@ impl IconExtractor {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid PE signature"));
         }
         
-        println!("Valid PE signature found");
+        debug!("Valid PE signature found");
         
         match Self::find_resource_section(&mut file, pe_offset) {
             Ok((resource_section_offset, resource_section_virtual_address)) => {
-                println!("Resource section found at offset: 0x{:08X}, VA: 0x{:08X}", 
+                debug!("Resource section found at offset: 0x{:08X}, VA: 0x{:08X}", 
                     resource_section_offset, resource_section_virtual_address);
                     
                 Ok(IconExtractor {
@@ -121,7 +125,7 @@ impl IconExtractor {
                 })
             }
             Err(e) => {
-                println!("Failed to find resource section: {}", e);
+                debug!("Failed to find resource section: {}", e);
                 // Try alternative method - check if resources are in the data directory
                 Self::try_alternative_resource_detection(&mut file, pe_offset, &path)
             }
@@ -129,7 +133,7 @@ impl IconExtractor {
     }
     
     fn try_alternative_resource_detection<P: AsRef<Path>>(file: &mut File, pe_offset: u32, path: P) -> io::Result<Self> {
-        println!("Attempting alternative resource detection...");
+        debug!("Attempting alternative resource detection...");
         
         // Read the optional header to get the resource data directory
         file.seek(SeekFrom::Start((pe_offset + 4) as u64))?;
@@ -164,7 +168,7 @@ impl IconExtractor {
                 "No resource directory found in data directory"));
         }
         
-        println!("Found resource directory in data directory: RVA=0x{:08X}, Size={}", 
+        debug!("Found resource directory in data directory: RVA=0x{:08X}, Size={}", 
             resource_rva, resource_size);
         
         // Now we need to find which section contains this RVA
@@ -202,7 +206,7 @@ impl IconExtractor {
         file.seek(SeekFrom::Current(2 + optional_header_size as i64))?;
         
         // Check each section
-        for i in 0..number_of_sections {
+        for _ in 0..number_of_sections {
             let mut section_name = [0u8; 8];
             file.read_exact(&mut section_name)?;
             
@@ -229,7 +233,7 @@ impl IconExtractor {
                 let section_name_str = String::from_utf8_lossy(&section_name)
                     .trim_end_matches('\0')
                     .to_string();
-                println!("Found target RVA in section: '{}'", section_name_str);
+                debug!("Found target RVA in section: '{}'", section_name_str);
                 return Ok((pointer_to_raw_data, virtual_address));
             }
         }
@@ -263,7 +267,7 @@ impl IconExtractor {
         // Skip optional header entirely
         file.seek(SeekFrom::Current(optional_header_size as i64))?;
         
-        println!("Found {} sections", number_of_sections);
+        debug!("Found {} sections", number_of_sections);
         
         // Read section headers
         for i in 0..number_of_sections {
@@ -292,17 +296,17 @@ impl IconExtractor {
                 .trim_end_matches('\0')
                 .to_string();
             
-            println!("Section {}: '{}' (raw bytes: {:?})", i, section_name_str, &section_name[0..8]);
-            println!("  Virtual Address: 0x{:08X}", virtual_address);
-            println!("  Pointer to Raw Data: 0x{:08X}", pointer_to_raw_data);
-            println!("  Size of Raw Data: {}", u32::from_le_bytes(size_of_raw_data));
+            trace!("Section {}: '{}' (raw bytes: {:?})", i, section_name_str, &section_name[0..8]);
+            trace!("  Virtual Address: 0x{:08X}", virtual_address);
+            trace!("  Pointer to Raw Data: 0x{:08X}", pointer_to_raw_data);
+            trace!("  Size of Raw Data: {}", u32::from_le_bytes(size_of_raw_data));
             
             // Check if this is the resource section (try multiple variations)
             let name_lower = section_name_str.to_lowercase();
             if name_lower.starts_with(".rsrc") || 
                section_name_str.starts_with(".rsrc") ||
                &section_name[0..5] == b".rsrc" {
-                println!("Found resource section: '{}'", section_name_str);
+                debug!("Found resource section: '{}'", section_name_str);
                 return Ok((pointer_to_raw_data, virtual_address));
             }
         }
@@ -367,11 +371,11 @@ impl IconExtractor {
     }
     
     pub fn extract_largest_icon(&mut self) -> io::Result<Vec<u8>> {
-        println!("Starting icon extraction...");
+        debug!("Starting icon extraction...");
         
         // Read root resource directory
         let root_dir = self.read_resource_directory(0)?;
-        println!("Root resource directory: {} name entries, {} ID entries", 
+        debug!("Root resource directory: {} name entries, {} ID entries", 
             root_dir.number_of_name_entries, root_dir.number_of_id_entries);
         
         // List all resource types first for debugging
@@ -385,12 +389,12 @@ impl IconExtractor {
         for i in 0..(root_dir.number_of_name_entries + root_dir.number_of_id_entries) {
             let entry = self.read_resource_directory_entry(current_offset)?;
             
-            println!("Resource entry {}: ID/Name=0x{:08X}, Offset=0x{:08X}", 
+            trace!("Resource entry {}: ID/Name=0x{:08X}, Offset=0x{:08X}", 
                 i, entry.name_or_id, entry.offset_to_data_or_subdirectory);
             
             if (entry.name_or_id & 0x80000000) == 0 && entry.name_or_id == RT_GROUP_ICON as u32 {
                 group_icon_offset = Some(entry.offset_to_data_or_subdirectory & 0x7FFFFFFF);
-                println!("Found RT_GROUP_ICON at offset: 0x{:08X}", group_icon_offset.unwrap());
+                debug!("Found RT_GROUP_ICON at offset: 0x{:08X}", group_icon_offset.unwrap());
                 break;
             }
             current_offset += 8; // Size of ResourceDirectoryEntry
@@ -402,7 +406,7 @@ impl IconExtractor {
         
         // Read icon group directory
         let group_dir = self.read_resource_directory(group_icon_offset)?;
-        println!("Icon group directory: {} name entries, {} ID entries", 
+        debug!("Icon group directory: {} name entries, {} ID entries", 
             group_dir.number_of_name_entries, group_dir.number_of_id_entries);
         
         if group_dir.number_of_id_entries == 0 {
@@ -412,16 +416,15 @@ impl IconExtractor {
         // Get first icon group
         let group_entry_offset = group_icon_offset + 16;
         let group_entry = self.read_resource_directory_entry(group_entry_offset)?;
-        println!("First icon group entry: ID/Name=0x{:08X}, Offset=0x{:08X}", 
+        debug!("First icon group entry: ID/Name=0x{:08X}, Offset=0x{:08X}", 
             group_entry.name_or_id, group_entry.offset_to_data_or_subdirectory);
         
         // Read the actual icon group data
-        let group_data_dir = self.read_resource_directory(group_entry.offset_to_data_or_subdirectory & 0x7FFFFFFF)?;
         let group_data_entry_offset = (group_entry.offset_to_data_or_subdirectory & 0x7FFFFFFF) + 16;
         let group_data_entry = self.read_resource_directory_entry(group_data_entry_offset)?;
         let group_data_info = self.read_resource_data_entry(group_data_entry.offset_to_data_or_subdirectory)?;
         
-        println!("Icon group data: RVA=0x{:08X}, Size={}", 
+        debug!("Icon group data: RVA=0x{:08X}, Size={}", 
             group_data_info.offset_to_data, group_data_info.size);
         
         let group_data = self.read_data(group_data_info.offset_to_data, group_data_info.size)?;
@@ -432,7 +435,7 @@ impl IconExtractor {
         }
         
         let count = u16::from_le_bytes([group_data[4], group_data[5]]) as usize;
-        println!("Found {} icons in group", count);
+        debug!("Found {} icons in group", count);
         
         let mut group_entries = Vec::new();
         
@@ -456,7 +459,7 @@ impl IconExtractor {
                 id: u16::from_le_bytes([group_data[base + 12], group_data[base + 13]]),
             };
             
-            println!("Icon {}: {}x{}, {} bytes, ID={}", 
+            trace!("Icon {}: {}x{}, {} bytes, ID={}", 
                 i, entry.width, entry.height, entry.bytes_in_res, entry.id);
             
             group_entries.push(entry);
@@ -474,7 +477,7 @@ impl IconExtractor {
             })
             .unwrap();
         
-        println!("Selected largest icon: {}x{}, {} bytes, ID={}", 
+        debug!("Selected largest icon: {}x{}, {} bytes, ID={}", 
             largest_entry.width, largest_entry.height, largest_entry.bytes_in_res, largest_entry.id);
         
         // Now find and extract the actual icon data
@@ -485,11 +488,11 @@ impl IconExtractor {
     }
     
     fn list_resource_types(&mut self) -> io::Result<()> {
-        println!("Listing all resource types...");
+        debug!("Listing all resource types...");
         let root_dir = self.read_resource_directory(0)?;
         let mut current_offset = 16u32;
         
-        for i in 0..(root_dir.number_of_name_entries + root_dir.number_of_id_entries) {
+        for _ in 0..(root_dir.number_of_name_entries + root_dir.number_of_id_entries) {
             let entry = self.read_resource_directory_entry(current_offset)?;
             
             if (entry.name_or_id & 0x80000000) == 0 {
@@ -517,9 +520,9 @@ impl IconExtractor {
                     24 => "RT_MANIFEST".to_string(),
                     _ => format!("UNKNOWN({})", entry.name_or_id),
                 };
-                println!("  Resource type: {}", resource_type);
+                trace!("  Resource type: {}", resource_type);
             } else {
-                println!("  Named resource: 0x{:08X}", entry.name_or_id);
+                trace!("  Named resource: 0x{:08X}", entry.name_or_id);
             }
             
             current_offset += 8;
@@ -557,7 +560,6 @@ impl IconExtractor {
             
             if (entry.name_or_id & 0x80000000) == 0 && entry.name_or_id == icon_id as u32 {
                 // Found the icon, get its data
-                let lang_dir = self.read_resource_directory(entry.offset_to_data_or_subdirectory & 0x7FFFFFFF)?;
                 let lang_entry_offset = (entry.offset_to_data_or_subdirectory & 0x7FFFFFFF) + 16;
                 let lang_entry = self.read_resource_directory_entry(lang_entry_offset)?;
                 let data_entry = self.read_resource_data_entry(lang_entry.offset_to_data_or_subdirectory)?;
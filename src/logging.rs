@@ -0,0 +1,99 @@
+// Messages go through the `log` facade: warnings, notes on what a command is
+// doing (info) and details for tracking down problems (debug and trace, such
+// as the icon extractor's PE parsing). The console shows them down to the
+// level picked with -v and -q; --log-file gets everything, for bug reports.
+use crate::progress;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+struct Logger {
+    console: LevelFilter,
+    file: Option<Mutex<LineWriter<File>>>,
+    start: Instant,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Set while stdout carries output for another program, such as `bench --json -`
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a record comes from execompress rather than a dependency, whose
+/// debug output is left out.
+fn ours(metadata: &Metadata) -> bool {
+    metadata.target().starts_with("execompress")
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (ours(metadata) || metadata.level() <= Level::Info)
+            && (metadata.level() <= self.console || self.file.is_some())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= self.console {
+            progress::suspend(|| match record.level() {
+                Level::Error => eprintln!("Error: {}", record.args()),
+                Level::Warn => eprintln!("Warning: {}", record.args()),
+                Level::Info if STDOUT_RESERVED.load(Ordering::Relaxed) => eprintln!("{}", record.args()),
+                Level::Info => println!("{}", record.args()),
+                Level::Debug => eprintln!("Debug: {}", record.args()),
+                Level::Trace => eprintln!("Trace: {}", record.args()),
+            });
+        }
+        if let Some(file) = &self.file {
+            let secs = self.start.elapsed().as_secs_f64();
+            let _ = writeln!(file.lock().unwrap(), "{:>9.3} {:<5} {}: {}", secs, record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Sets up logging. The console shows errors, warnings and info by default;
+/// each -v adds a level of detail (debug, then trace) and each -q removes
+/// one (info, then warnings).
+pub fn init(verbose: u8, quiet: u8, log_file: Option<&Path>) -> io::Result<()> {
+    let levels = [LevelFilter::Error, LevelFilter::Warn, LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace];
+    let console = levels[(2 + verbose as usize).saturating_sub(quiet as usize).min(levels.len() - 1)];
+    let file = match log_file {
+        Some(path) => {
+            let mut file = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            let args: Vec<String> = std::env::args().collect();
+            writeln!(file, "execompress {} ({}): {}", env!("CARGO_PKG_VERSION"), std::env::consts::OS, args.join(" "))?;
+            Some(Mutex::new(LineWriter::new(file)))
+        }
+        None => None,
+    };
+    let max_level = if file.is_some() { LevelFilter::Trace } else { console };
+    let logger = LOGGER.get_or_init(|| Logger {
+        console,
+        file,
+        start: Instant::now(),
+    });
+    log::set_logger(logger).map_err(|e| io::Error::other(e.to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Sends info messages to stderr from now on, leaving stdout to output
+/// that another program reads.
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+/// Whether messages at `level` are shown on the console.
+pub fn shows(level: Level) -> bool {
+    LOGGER.get().is_none_or(|logger| level <= logger.console)
+}
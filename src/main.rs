@@ -1,41 +1,124 @@
-use clap::Parser;
-use base64::{engine::general_purpose::STANDARD, Engine as _};
-use std::{fs, io::Write, path::PathBuf};
-use std::sync::{Arc, Mutex};
-use std::process::Command;
-use xz2::write::XzEncoder;
-use zstd::stream::Encoder;
-use brotli::CompressorWriter;
-use std::fs::{write, File};
-use walkdir::WalkDir;
+use clap::builder::PossibleValuesParser;
+use clap::parser::ValueSource;
+use clap::error::ErrorKind;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::collections::BTreeMap;
+use std::{fs, io::{BufRead, BufReader, BufWriter}, path::{Path, PathBuf}};
+use std::fs::File;
+mod archive;
+mod auto;
+mod batch;
+mod bench;
+mod codec;
+mod compare;
+mod config;
+mod container;
+mod error;
+mod export;
+mod extras;
+mod filter;
 mod icoextractor;
+mod logging;
+mod patch;
+mod pe;
+mod profile;
+mod progress;
+mod report;
+mod rules;
+mod spill;
 mod stub;
 mod version_extractor;
+use crate::auto::Objective;
+use crate::config::Config;
+use crate::container::Writer;
+use crate::error::{PackError, Reading};
+use crate::extras::{ExtraFile, Selection};
 use crate::icoextractor::IconExtractor;
-use crate::version_extractor::extract_version_info;
-use rayon::*;
-use rayon::prelude::*;
+use crate::profile::Profile;
+use crate::progress::Progress;
+use crate::rules::Rule;
+use crate::spill::{Spill, SpilledEntry};
+use crate::stub::StubSettings;
+use crate::version_extractor::{extract_version_info, VersionInfo};
+use execompress_codecs::{self as codecs, Codec, Tuning};
+use log::{debug, error, info};
 use rayon::ThreadPoolBuilder;
+use std::time::Instant;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Input executable
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    pack: PackArgs,
+
+    /// Show more detail: -v for debug messages, -vv for trace
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Show less: -q hides progress and info messages, -qq warnings too
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Write every message, down to trace level, to this file (for bug reports)
+    #[arg(long, value_name = "FILE", global = true)]
+    log_file: Option<PathBuf>,
+}
+
+// Pack settings, given at the top level or to `pack`
+#[derive(clap::Args)]
+struct PackArgs {
+    /// Project config file with pack settings [default: execompress.toml in the current directory, if present]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Apply the [target.<name>] section of the config file over its top level
+    #[arg(long)]
+    target: Option<String>,
+
+    /// Input executable (required here or as `input` in the config file)
     #[arg(short, long)]
-    input: PathBuf,
+    input: Option<PathBuf>,
 
-	///	Extra directory containing files and directories to pack/unpack together
-	#[arg(short, long)]
+    /// Extra directory containing files and directories to pack/unpack together
+    #[arg(short, long)]
     extra_dir: Option<PathBuf>,
 
-    /// Output compressed executable
+    /// Pack a file or directory at a path in the bundle: '<src>[:<dest>]', dest defaulting to the source's name
+    #[arg(long, value_name = "SRC[:DEST]")]
+    add: Vec<String>,
+
+    /// Read '<src>[:<dest>]' lines, as --add, from a file or '-' for stdin; sources are relative to the file
+    #[arg(long, value_name = "LIST")]
+    files_from: Vec<PathBuf>,
+
+    /// Pack the files of a zip, tar, tar.gz or tar.zst archive without unpacking it, at the root or under dest
+    #[arg(long, value_name = "FILE[:DEST]")]
+    add_archive: Vec<String>,
+
+    /// Only pack extra files whose path in the bundle matches one of these globs
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Leave out extra files whose path in the bundle matches this glob; wins over --include
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Print the files that would be packed and exit without packing
+    #[arg(long)]
+    list_files: bool,
+
+    /// Output compressed executable (required here or as `output` in the config file)
     #[arg(short, long)]
-    output: PathBuf,
+    output: Option<PathBuf>,
 
-    /// Compression level: 1-9 (lzma, default) 1-22 (--zstd), 0-11 (--brotli)
+    /// Compression level: 1-9 (lzma, default) 1-22 (--zstd), 0-11 (--brotli), 0-12 (--lz4, 3+ uses LZ4 HC)
     #[arg(short, long, default_value = "3")]
     level: u32,
 
-    /// Amount of thread used to pack binary and extra directory
+    /// Amount of thread used to pack binary and extra directory (xz and zstd also split the main binary across them)
     #[arg(short, long, default_value = "4")]
     parallel: usize,
 
@@ -47,6 +130,74 @@ struct Args {
     #[arg(long)]
     brotli: bool,
 
+    /// Use lz4 instead of lzma (fastest decompression, larger output)
+    #[arg(long)]
+    lz4: bool,
+
+    /// Compression algorithm; `auto` trial-compresses a sample with every codec and picks by --objective (ignores --level)
+    #[arg(long, value_parser = algo_parser(), conflicts_with_all = ["zstd", "brotli", "lz4"])]
+    algo: Option<String>,
+
+    /// What --algo auto optimizes for
+    #[arg(long, value_enum, default_value = "balanced")]
+    objective: Objective,
+
+    /// Pick the smallest codec and level expected to finish compressing within this many seconds, estimated from a sample
+    #[arg(long, conflicts_with = "level")]
+    time_budget: Option<f64>,
+
+    /// Fail without writing the output if it would be larger than this many bytes; accepts K, M and G suffixes
+    #[arg(long, value_parser = parse_size)]
+    max_output_size: Option<u64>,
+
+    /// Build the stub again in a clean target directory, pack a second time with it and fail without writing the output unless both results are byte-identical
+    #[arg(long)]
+    check_reproducible: bool,
+
+    /// Write a JSON report of the pack (entries, sizes, timings, hashes) to this file, or `-` for stdout
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Named settings: fastest, fast, balanced, small, smallest, or a [profile.<name>] from execompress.toml; explicit flags override it
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Use the xz extreme preset (slower, slightly smaller)
+    #[arg(long)]
+    xz_extreme: bool,
+
+    /// xz dictionary size in bytes; accepts K, M and G suffixes (e.g. 64M)
+    #[arg(long, value_parser = parse_size)]
+    xz_dict_size: Option<u64>,
+
+    /// Enable zstd long distance matching, optionally with a window log of 10-30 [default window log: 27]
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "27", value_name = "WINDOWLOG")]
+    zstd_long: Option<u32>,
+
+    /// Brotli window size as a power of two: 10-24, or up to 30 with --brotli-large-window [default: 22]
+    #[arg(long)]
+    brotli_lgwin: Option<u32>,
+
+    /// Use large-window brotli (window up to 1 GiB, defaults --brotli-lgwin to 30)
+    #[arg(long)]
+    brotli_large_window: bool,
+
+    /// Codec for extra files matching a glob: '<glob>=<codec>[:<level>]', or '<glob>=auto' to keep the smallest of all codecs; first match wins
+    #[arg(long = "rule", value_name = "RULE")]
+    rules: Vec<String>,
+
+    /// Files and directories mapped into the bundle by the config file, --add and --files-from
+    #[arg(skip)]
+    extras_map: Vec<config::Mapping>,
+
+    /// Do not apply the x86/x64/ARM64 branch filter to PE and ELF binaries
+    #[arg(long)]
+    no_bcj: bool,
+
+    /// Compress the extra files together as one stream instead of one per file (smaller, but extracted on one thread)
+    #[arg(long)]
+    solid: bool,
+
     /// When input file is GUI app, suppress command line window
     #[arg(long)]
     gui: bool,
@@ -79,6 +230,10 @@ struct Args {
     #[arg(long)]
     copyright: Option<String>,
 
+    /// Copy version info strings that are not given from the input executable
+    #[arg(long)]
+    input_version_info: bool,
+
     /// Require administrator privileges (adds requireAdministrator to manifest)
     #[arg(long)]
     require_admin: bool,
@@ -88,49 +243,369 @@ struct Args {
     manifest: bool,
 }
 
-fn validate_compression_level(level: u32, brotli: bool, zstd: bool) -> Result<(), String> {
-    if brotli {
-        if level > 11 {
-            return Err(format!(
-                "Invalid compression level {} for brotli. Brotli supports levels 0-11.\n\
-                 Level 0 = fastest/lowest compression\n\
-                 Level 11 = slowest/highest compression (best ratio)",
-                level
-            ));
+/// Codecs compiled into this build, plus `auto`.
+fn algo_parser() -> PossibleValuesParser {
+    let mut names: Vec<&'static str> = codecs::registry().iter().map(|c| c.name()).collect();
+    names.push("auto");
+    PossibleValuesParser::new(names)
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Pack an executable; the same as giving the options without a subcommand
+    Pack(Box<PackArgs>),
+
+    /// Create a binary delta patch between two packed executables
+    DiffPatch {
+        /// Previously released packed executable
+        old: PathBuf,
+
+        /// New packed executable
+        new: PathBuf,
+
+        /// Output patch file
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// zstd level used for changed entries: 1-22
+        #[arg(short, long, default_value = "19")]
+        level: i32,
+    },
+
+    /// Rebuild a packed executable from an older release and a patch
+    ApplyPatch {
+        /// Previously released packed executable the patch was created from
+        old: PathBuf,
+
+        /// Patch file created by diff-patch
+        patch: PathBuf,
+
+        /// Output packed executable
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// List entry and metadata differences between two packed executables
+    Compare {
+        /// Packed executable to compare from
+        a: PathBuf,
+
+        /// Packed executable to compare to
+        b: PathBuf,
+    },
+
+    /// Convert a packed executable's files and metadata into a zip, tar, tar.gz or tar.zst archive
+    Export {
+        /// Packed executable to export
+        input: PathBuf,
+
+        /// Output archive; its extension picks the format
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Pack every [[batch]] entry of a config file, building each distinct stub once
+    Batch {
+        /// Config file with [[batch]] entries [default: execompress.toml in the current directory]
+        manifest: Option<PathBuf>,
+
+        /// Apply the [target.<name>] section of the config file under every entry
+        #[arg(long)]
+        target: Option<String>,
+
+        /// Packs run at the same time; each also uses its own `parallel` threads
+        #[arg(short, long, default_value = "2")]
+        jobs: usize,
+
+        /// Write a JSON report with every entry's pack report or error to this file, or `-` for stdout
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+    },
+
+    /// Pack with every codec and level, then time decompression and launch of each result
+    Bench {
+        /// Input executable
+        input: PathBuf,
+
+        /// Extra directory containing files and directories to pack/unpack together
+        #[arg(short, long)]
+        extra_dir: Option<PathBuf>,
+
+        /// Amount of thread used to pack extra directory
+        #[arg(short, long, default_value = "4")]
+        parallel: usize,
+
+        /// Launches of each packed output to time (Linux only; the input must exit on its own)
+        #[arg(long, default_value = "3")]
+        runs: usize,
+
+        /// Also write results as JSON to this file (`-` for stdout)
+        #[arg(long)]
+        json: Option<PathBuf>,
+    },
+}
+
+fn validate_compression_level(level: u32, codec: &dyn Codec) -> Result<(), String> {
+    let levels = codec.levels();
+    if !levels.contains(&level) {
+        let mut message = format!(
+            "Invalid compression level {} for {}. {} supports levels {}-{}.",
+            level,
+            codec.name(),
+            codec.name(),
+            levels.start(),
+            levels.end()
+        );
+        if !codec.level_hint().is_empty() {
+            message.push('\n');
+            message.push_str(codec.level_hint());
         }
-    } else if zstd {
-        if level < 1 || level > 22 {
-            return Err(format!(
-                "Invalid compression level {} for zstd. Zstd supports levels 1-22.\n\
-                 Level 1 = fastest/lowest compression\n\
-                 Level 22 = slowest/highest compression (best ratio)\n\
-                 Recommended: 3-19 for balanced performance",
-                level
-            ));
+        return Err(message);
+    }
+    Ok(())
+}
+
+/// Parses a byte count with an optional K/M/G (binary) suffix.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1u64 << 10,
+                'M' => 1u64 << 20,
+                'G' => 1u64 << 30,
+                _ => return Err(format!("Unknown size suffix '{}'", c)),
+            };
+            (&value[..i], multiplier)
         }
-    } else {
-        // LZMA (default)
-        if level > 9 {
-            return Err(format!(
-                "Invalid compression level {} for lzma. LZMA supports levels 0-9.\n\
-                 Level 0 = fastest/lowest compression\n\
-                 Level 9 = slowest/highest compression (best ratio)\n\
-                 Recommended: 3-6 for balanced performance",
-                level
-            ));
+        _ => (value, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size '{}'", value))
+}
+
+fn extract_icon(input_path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut extractor = IconExtractor::new(input_path)?;
+    extractor.extract_largest_icon()
+}
+
+/// Applies `profile` to every setting not set `explicit`ly and returns the
+/// flags that overrode it.
+fn apply_profile(args: &mut PackArgs, explicit: impl Fn(&str) -> bool, profile: &Profile) -> Result<Vec<&'static str>, String> {
+    let mut overrides = vec![];
+
+    let codec_overridden = ["algo", "zstd", "brotli", "lz4"].iter().any(|id| explicit(id));
+    if codec_overridden {
+        overrides.push("codec");
+    } else if let Some(codec) = &profile.codec {
+        args.algo = Some(codec.clone());
+    }
+    // A profile's level is chosen for its codec, so it is dropped along with it
+    if explicit("level") {
+        overrides.push("--level");
+    } else if let (Some(level), false) = (profile.level, codec_overridden) {
+        args.level = level;
+    }
+    if explicit("no_bcj") {
+        overrides.push("--no-bcj");
+    } else if let Some(bcj) = profile.bcj {
+        args.no_bcj = !bcj;
+    }
+    if explicit("solid") {
+        overrides.push("--solid");
+    } else if let Some(solid) = profile.solid {
+        args.solid = solid;
+    }
+    if explicit("xz_extreme") {
+        overrides.push("--xz-extreme");
+    } else if let Some(extreme) = profile.xz_extreme {
+        args.xz_extreme = extreme;
+    }
+    if explicit("xz_dict_size") {
+        overrides.push("--xz-dict-size");
+    } else if let Some(size) = &profile.xz_dict_size {
+        args.xz_dict_size = Some(parse_size(size)?);
+    }
+    if explicit("zstd_long") {
+        overrides.push("--zstd-long");
+    } else if let Some(window_log) = profile.zstd_long {
+        args.zstd_long = Some(window_log);
+    }
+    if explicit("brotli_lgwin") {
+        overrides.push("--brotli-lgwin");
+    } else if let Some(lgwin) = profile.brotli_lgwin {
+        args.brotli_lgwin = Some(lgwin);
+    }
+    if explicit("brotli_large_window") {
+        overrides.push("--brotli-large-window");
+    } else if let Some(large_window) = profile.brotli_large_window {
+        args.brotli_large_window = large_window;
+    }
+    Ok(overrides)
+}
+
+/// Applies the config file settings to every option not given on the
+/// command line. Returns the ids of the options it set, which a profile
+/// must then leave alone.
+fn apply_config(args: &mut PackArgs, matches: &ArgMatches, config: &Config) -> Result<Vec<&'static str>, String> {
+    let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut applied = vec![];
+
+    if let (Some(input), false) = (&config.input, explicit("input")) {
+        args.input = Some(input.clone());
+        applied.push("input");
+    }
+    if let (Some(output), false) = (&config.output, explicit("output")) {
+        args.output = Some(output.clone());
+        applied.push("output");
+    }
+    let codec_given = ["algo", "zstd", "brotli", "lz4"].iter().any(|id| explicit(id));
+    if let (Some(codec), false) = (&config.codec, codec_given) {
+        args.algo = Some(codec.clone());
+        applied.push("algo");
+    }
+    if let (Some(level), false) = (config.level, explicit("level")) {
+        args.level = level;
+        applied.push("level");
+    }
+    if let (Some(objective), false) = (&config.objective, explicit("objective")) {
+        args.objective = Objective::from_str(objective, false)?;
+        applied.push("objective");
+    }
+    if let (Some(secs), false) = (config.time_budget, explicit("time_budget")) {
+        args.time_budget = Some(secs);
+        applied.push("time_budget");
+    }
+    if let (Some(size), false) = (&config.max_output_size, explicit("max_output_size")) {
+        args.max_output_size = Some(parse_size(size)?);
+        applied.push("max_output_size");
+    }
+    if let (Some(parallel), false) = (config.parallel, explicit("parallel")) {
+        args.parallel = parallel;
+        applied.push("parallel");
+    }
+    if let (Some(bcj), false) = (config.bcj, explicit("no_bcj")) {
+        args.no_bcj = !bcj;
+        applied.push("no_bcj");
+    }
+    if let (Some(solid), false) = (config.solid, explicit("solid")) {
+        args.solid = solid;
+        applied.push("solid");
+    }
+    if let (Some(extreme), false) = (config.xz_extreme, explicit("xz_extreme")) {
+        args.xz_extreme = extreme;
+        applied.push("xz_extreme");
+    }
+    if let (Some(size), false) = (&config.xz_dict_size, explicit("xz_dict_size")) {
+        args.xz_dict_size = Some(parse_size(size)?);
+        applied.push("xz_dict_size");
+    }
+    if let (Some(window_log), false) = (config.zstd_long, explicit("zstd_long")) {
+        args.zstd_long = Some(window_log);
+        applied.push("zstd_long");
+    }
+    if let (Some(lgwin), false) = (config.brotli_lgwin, explicit("brotli_lgwin")) {
+        args.brotli_lgwin = Some(lgwin);
+        applied.push("brotli_lgwin");
+    }
+    if let (Some(large_window), false) = (config.brotli_large_window, explicit("brotli_large_window")) {
+        args.brotli_large_window = large_window;
+        applied.push("brotli_large_window");
+    }
+
+    let extras = config.extras.clone().unwrap_or_default();
+    if let (Some(dir), false) = (extras.dir, explicit("extra_dir")) {
+        args.extra_dir = Some(dir);
+    }
+    args.extras_map = extras.map.unwrap_or_default();
+
+    let version = config.version.clone().unwrap_or_default();
+    for (id, value, arg) in [
+        ("product_name", version.product_name, &mut args.product_name),
+        ("company_name", version.company_name, &mut args.company_name),
+        ("file_description", version.file_description, &mut args.file_description),
+        ("product_version", version.product_version, &mut args.product_version),
+        ("file_version", version.file_version, &mut args.file_version),
+        ("copyright", version.copyright, &mut args.copyright),
+    ] {
+        if let (Some(value), false) = (value, explicit(id)) {
+            *arg = Some(value);
+        }
+    }
+    if let (Some(from_input), false) = (version.from_input, explicit("input_version_info")) {
+        args.input_version_info = from_input;
+    }
+
+    let manifest = config.manifest.clone().unwrap_or_default();
+    let runtime = config.runtime.clone().unwrap_or_default();
+    for (id, value, arg) in [
+        ("manifest", manifest.embed, &mut args.manifest),
+        ("require_admin", manifest.require_admin, &mut args.require_admin),
+        ("gui", runtime.gui, &mut args.gui),
+        ("cleanup", runtime.cleanup, &mut args.cleanup),
+    ] {
+        if let (Some(value), false) = (value, explicit(id)) {
+            *arg = value;
+        }
+    }
+    Ok(applied)
+}
+
+/// Prints the input and the extra files `--list-files` would pack, with their
+/// paths in the bundle and sizes.
+fn list_files(input: &Path, files: &[ExtraFile]) -> std::io::Result<()> {
+    println!("{:>12}  {}  (main executable)", fs::metadata(input)?.len(), input.display());
+    let mut total = 0;
+    for file in files {
+        total += file.size;
+        match &file.link {
+            Some(target) => println!("{:>12}  {} -> {}  ({})", file.size, file.name, target, file.source),
+            None => println!("{:>12}  {}  ({})", file.size, file.name, file.source),
         }
     }
+    println!("{} extra files, {} bytes", files.len(), total);
     Ok(())
 }
 
-fn extract_icon(input_path: String, output_path: String) -> std::io::Result<()> {
-    let mut extractor = IconExtractor::new(input_path)?;
-    let ico_data = extractor.extract_largest_icon()?;
+/// Error for an output over the --max-output-size limit, listing what takes
+/// up the most space in it.
+fn size_breakdown(stub_len: usize, main: &SpilledEntry, extras: &[SpilledEntry], total: u64, limit: u64) -> String {
+    let mut message = format!(
+        "output would be {} bytes, {} bytes over the --max-output-size limit of {} bytes",
+        total,
+        total - limit,
+        limit
+    );
 
-    let mut output_file = File::create(output_path)?;
-    output_file.write_all(&ico_data)?;
+    let mut parts = vec![
+        ("stub loader".to_string(), stub_len as u64),
+        (format!("{} (main executable, {})", main.name, main.codec()), main.len),
+    ];
+    for entry in extras {
+        parts.push((format!("{} ({})", entry.name, entry.codec()), entry.len));
+    }
+    let payload: u64 = parts.iter().map(|(_, size)| size).sum();
+    parts.push(("markers, names and metadata".to_string(), total - payload));
+    parts.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
 
-    return Ok(());
+    message.push_str("\nLargest contributors:");
+    for (name, size) in parts.iter().take(10) {
+        message.push_str(&format!("\n  {:>12} {:>6.2}%  {}", size, *size as f64 / total as f64 * 100.0, name));
+    }
+    if parts.len() > 10 {
+        let rest: u64 = parts[10..].iter().map(|(_, size)| size).sum();
+        message.push_str(&format!(
+            "\n  {:>12} {:>6.2}%  {} more entries",
+            rest,
+            rest as f64 / total as f64 * 100.0,
+            parts.len() - 10
+        ));
+    }
+    message
 }
 
 //fn extract_icon(input: &PathBuf, icon_path: &str) -> std::io::Result<()> {
@@ -143,263 +618,605 @@ fn extract_icon(input_path: String, output_path: String) -> std::io::Result<()>
 //    Ok(())
 //}
 
-fn main() -> std::io::Result<()> {
-    let args = Args::parse();
-    std::fs::create_dir_all("configs")?;
-
-    // Validate compression level
-    if let Err(e) = validate_compression_level(args.level, args.brotli, args.zstd) {
+fn main() {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(e) = logging::init(args.verbose, args.quiet, args.log_file.as_deref()) {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
+    if let Err(e) = execompress(args, &matches) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    // Display compression algorithm information
-    let algo_name = if args.brotli {
-        "Brotli"
-    } else if args.zstd {
-        "Zstd"
+/// Runs the command in `args`, parsed from `matches`.
+fn execompress(args: Args, matches: &ArgMatches) -> Result<(), PackError> {
+    let (mut args, matches) = match args.command {
+        Some(Commands::Pack(pack)) => (*pack, matches.subcommand_matches("pack").unwrap()),
+        Some(Commands::DiffPatch { old, new, output, level }) => {
+            validate_compression_level(level as u32, codecs::by_name("zstd").unwrap())?;
+            return Ok(patch::diff_patch(&old, &new, &output, level)?);
+        }
+        Some(Commands::ApplyPatch { old, patch, output }) => {
+            return Ok(patch::apply_patch(&old, &patch, &output)?);
+        }
+        Some(Commands::Compare { a, b }) => {
+            return Ok(compare::compare(&a, &b)?);
+        }
+        Some(Commands::Export { input, output }) => {
+            return Ok(export::export(&input, &output)?);
+        }
+        Some(Commands::Batch { manifest, target, jobs, report }) => {
+            return batch::batch(manifest.as_deref(), target.as_deref(), jobs, report.as_deref());
+        }
+        Some(Commands::Bench { input, extra_dir, parallel, runs, json }) => {
+            return Ok(bench::bench(&input, extra_dir.as_deref(), parallel, runs, json.as_deref())?);
+        }
+        None => (args.pack, matches),
+    };
+    let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    let config = config::load(args.config.as_deref())
+        .map_err(|e| PackError::Settings(e.to_string()))?
+        .for_target(args.target.as_deref())?;
+    let from_config = apply_config(&mut args, matches, &config)?;
+    if args.input.is_none() || (args.output.is_none() && !args.list_files) {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "an input and an output executable are required: pass --input and --output, or set `input` and `output` in the config file",
+            )
+            .exit();
+    }
+
+    let set = |id: &str| explicit(id) || from_config.contains(&id);
+    // --list-files only prints what would be packed
+    let Some(job) = prepare(args, set, &config)? else {
+        return Ok(());
+    };
+    let start = Instant::now();
+    let stub = job.stub.build()?;
+    let stub_secs = start.elapsed().as_secs_f64();
+    let rebuilt = if job.args.check_reproducible {
+        info!("Building the stub again in a clean target directory to check that it is reproducible...");
+        Some(job.stub.rebuild()?)
     } else {
-        "LZMA"
+        None
     };
-    println!("Using {} compression (level {})", algo_name, args.level);
+    let mut outcome = run(&job, &stub, rebuilt.as_deref())?;
+    let compression_ratio = (outcome.main_size as f64 / outcome.input_size as f64) * 100.0;
+    info!("Original size: {} bytes, Compressed: {} bytes ({:.2}% of original)",
+          outcome.input_size, outcome.main_size, compression_ratio);
+    info!("Compressed executable written to {:?}", job.output());
+    if let Some(path) = &job.args.report {
+        outcome.report["stub"]["build_secs"] = serde_json::json!(stub_secs);
+        report::write(path, &outcome.report)?;
+    }
+    Ok(())
+}
 
-    // Extract version info from input executable (for use as defaults)
-    let input_version_info = extract_version_info(&args.input);
+/// A pack with every setting resolved, ready to compress.
+struct Job {
+    args: PackArgs,
+    files: Vec<ExtraFile>,
+    codec: &'static dyn Codec,
+    tuning: Tuning,
+    /// Settings recorded in the output for compare/inspection
+    metadata: BTreeMap<String, String>,
+    main_filter: Option<filter::Filter>,
+    source_date_epoch: Option<u32>,
+    stub: StubSettings,
+    /// Given while preparing, for the report
+    warnings: Vec<String>,
+    /// Time spent preparing, counted in the pack's duration
+    prepare_secs: f64,
+}
 
-    let input_data = fs::read(&args.input)?;
-    let _ = std::fs::remove_file("stub_loader/icon.ico");
-    let x = extract_icon(args.input.display().to_string(), "stub_loader/icon.ico".to_string());
-    match x {
-        Ok(x) => {},
-        Err(x) => { println!("{:?}", x); }
+impl Job {
+    fn input(&self) -> &Path {
+        self.args.input.as_deref().unwrap()
     }
 
-    if args.gui {
-        let _ = std::fs::remove_file("stub_loader/gui.txt");
-        write("stub_loader/gui.txt", "true")?;
-    } else {
-        let _ = std::fs::remove_file("stub_loader/gui.txt");
+    fn output(&self) -> &Path {
+        self.args.output.as_deref().unwrap()
     }
+}
 
-    if args.cleanup {
-        let _ = std::fs::remove_file("stub_loader/cleanup.txt");
-        write("stub_loader/cleanup.txt", "true")?;
-    } else {
-        let _ = std::fs::remove_file("stub_loader/cleanup.txt");
+/// Sizes and report of a finished pack.
+struct Outcome {
+    input_size: u64,
+    main_size: u64,
+    size: u64,
+    report: serde_json::Value,
+}
+
+/// Resolves the pack settings in `args`, already merged with `config`, into
+/// a job: collects the extra files, picks the codec and gathers the stub's
+/// settings. `set` tells whether the command line or the config file gave a
+/// flag, so a profile leaves it alone. Returns `None` after `--list-files`.
+fn prepare(mut args: PackArgs, set: impl Fn(&str) -> bool, config: &Config) -> Result<Option<Job>, PackError> {
+    let start = Instant::now();
+    report::take_warnings();
+    let input = args.input.clone().unwrap();
+
+    // Settings recorded in the output for compare/inspection
+    let mut metadata = BTreeMap::new();
+    if let Some(target) = &args.target {
+        info!("Using config target '{}'", target);
+        metadata.insert("target".to_string(), target.clone());
+    }
+
+    // Globs from the command line and the config file both apply
+    let extras_config = config.extras.clone().unwrap_or_default();
+    let include: Vec<String> = args.include.iter().chain(extras_config.include.iter().flatten()).cloned().collect();
+    let exclude: Vec<String> = args.exclude.iter().chain(extras_config.exclude.iter().flatten()).cloned().collect();
+    let selection = Selection::new(&include, &exclude)?;
+    for spec in &args.add {
+        let mapping = extras::parse_mapping(spec, Path::new("")).map_err(|e| format!("Invalid --add '{}': {}", spec, e))?;
+        args.extras_map.push(mapping);
     }
+    for list in &args.files_from {
+        let mappings = extras::read_list(list).map_err(|e| PackError::extra(list, e))?;
+        args.extras_map.extend(mappings);
+    }
+    let archives: Vec<config::Mapping> = args
+        .add_archive
+        .iter()
+        .map(|spec| extras::parse_mapping(spec, Path::new("")).map_err(|e| format!("Invalid --add-archive '{}': {}", spec, e)))
+        .collect::<Result<_, _>>()?;
+    let mut files = extras::collect(args.extra_dir.as_deref(), &args.extras_map, &archives, &selection)?;
+    // The main executable is extracted next to the extra files, under the output's name
+    let main_name = args.output.as_deref().and_then(|o| o.file_name()).map(|n| n.to_string_lossy().to_string());
+    extras::check_conflicts(&files, main_name.as_deref().map(|name| (name, input.as_path())))?;
+    if args.list_files {
+        list_files(&input, &files).map_err(|e| PackError::input(&input, e))?;
+        return Ok(None);
+    }
+    if !include.is_empty() {
+        metadata.insert("include".to_string(), include.join(" "));
+    }
+    if !exclude.is_empty() {
+        metadata.insert("exclude".to_string(), exclude.join(" "));
+    }
+    let output = args.output.clone().unwrap();
 
-    // Write version info to files for build.rs to read
-    // Use provided value, or fall back to extracted value from input exe
-    if let Some(product_name) = &args.product_name {
-        write("stub_loader/product_name.txt", product_name)?;
-    } else if let Some(ref info) = input_version_info {
-        if let Some(ref product_name) = info.product_name {
-            write("stub_loader/product_name.txt", product_name)?;
+    // The input is compressed straight from the file. It is also mapped for
+    // codec selection and header checks, which only touch the parts they read
+    let input_file = File::open(&input).map_err(|e| PackError::input(&input, e))?;
+    // SAFETY: the input is only read and is not expected to change while packing
+    let input_map = unsafe { memmap2::Mmap::map(&input_file) }.map_err(|e| PackError::input(&input, e))?;
+    let input_data: &[u8] = &input_map;
+
+    // Where a level not given on the command line came from, since --algo
+    // auto and --time-budget replace it with their own
+    let mut level_source = set("level").then(|| "the config file".to_string());
+
+    // A profile only fills in what neither the command line nor the config sets
+    if let Some(name) = args.profile.clone().or_else(|| config.use_profile.clone()) {
+        let profile = profile::resolve(&name, config).map_err(|e| e.to_string())?;
+        let level = args.level;
+        let overrides = apply_profile(&mut args, set, &profile).map_err(|e| format!("profile '{}': {}", name, e))?;
+        if args.level != level {
+            level_source = Some(format!("profile '{}'", name));
+        }
+        if overrides.is_empty() {
+            info!("Using profile '{}'", name);
         } else {
-            let _ = std::fs::remove_file("stub_loader/product_name.txt");
+            info!("Using profile '{}' (overridden: {})", name, overrides.join(", "));
+            metadata.insert("profile_overrides".to_string(), overrides.join(","));
         }
-    } else {
-        let _ = std::fs::remove_file("stub_loader/product_name.txt");
+        metadata.insert("profile".to_string(), name);
     }
 
-    if let Some(company_name) = &args.company_name {
-        write("stub_loader/company_name.txt", company_name)?;
-    } else if let Some(ref info) = input_version_info {
-        if let Some(ref company_name) = info.company_name {
-            write("stub_loader/company_name.txt", company_name)?;
+    // https://reproducible-builds.org/specs/source-date-epoch/
+    let source_date_epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.trim().is_empty() => match value.trim().parse::<u64>() {
+            Ok(secs) => Some(secs.min(u32::MAX as u64) as u32),
+            Err(_) => return Err(format!("SOURCE_DATE_EPOCH must be a number of seconds, got '{}'", value).into()),
+        },
+        _ => None,
+    };
+
+    let tuning = Tuning {
+        xz_extreme: args.xz_extreme,
+        xz_dict_size: args.xz_dict_size.map(|size| size.min(u32::MAX as u64) as u32),
+        zstd_window_log: args.zstd_long,
+        brotli_lgwin: args.brotli_lgwin,
+        brotli_large_window: args.brotli_large_window,
+    };
+    tuning.validate()?;
+
+    // --zstd/--brotli/--lz4 are shorthands for --algo
+    let algo = args.algo.clone().unwrap_or_else(|| {
+        let name = if args.lz4 {
+            "lz4"
+        } else if args.brotli {
+            "brotli"
+        } else if args.zstd {
+            "zstd"
+        } else {
+            "lzma"
+        };
+        name.to_string()
+    });
+    let codec: &'static dyn Codec = if algo == "auto" || args.time_budget.is_some() {
+        // On a budget without --algo auto, a codec given by flag or profile is
+        // kept and only its level escalates; the goal is the smallest output
+        let explicit_codec = args.algo.is_some() || args.zstd || args.brotli || args.lz4;
+        let (candidates, objective) = if algo == "auto" {
+            (codecs::registry().to_vec(), args.objective)
+        } else if explicit_codec {
+            (vec![codecs::by_name(&algo).unwrap()], Objective::Size)
         } else {
-            let _ = std::fs::remove_file("stub_loader/company_name.txt");
+            (codecs::registry().to_vec(), Objective::Size)
+        };
+        let budget = args.time_budget.map(|secs| auto::Budget {
+            secs,
+            threads: args.parallel,
+        });
+        let chosen = auto::select(
+            input_data,
+            &files,
+            objective,
+            &tuning,
+            !args.no_bcj,
+            &candidates,
+            budget.as_ref(),
+        )?;
+        if let Some(source) = &level_source {
+            let picker = if args.time_budget.is_some() { "--time-budget" } else { "--algo auto" };
+            report::warn(format!("level {} from {} is ignored: {} picks the level", args.level, source, picker));
+        }
+        args.level = chosen.level;
+        if algo == "auto" {
+            metadata.insert("algo".to_string(), "auto".to_string());
         }
+        metadata.insert("objective".to_string(), objective.name().to_string());
+        if let Some(secs) = args.time_budget {
+            metadata.insert("time_budget".to_string(), secs.to_string());
+        }
+        chosen.codec
     } else {
-        let _ = std::fs::remove_file("stub_loader/company_name.txt");
+        codecs::by_name(&algo).unwrap()
+    };
+
+    // Validate compression level
+    validate_compression_level(args.level, codec)?;
+
+    // Rules from the command line take precedence over the config file
+    let rules: Vec<Rule> = args
+        .rules
+        .iter()
+        .chain(config.rules.iter().flatten())
+        .map(|spec| Rule::parse(spec, args.level))
+        .collect::<Result<_, _>>()?;
+    for file in &mut files {
+        file.rule = rules::find(&rules, &file.name).map(|rule| rule.choice);
+    }
+    if !rules.is_empty() {
+        let specs: Vec<&str> = rules.iter().map(|r| r.spec.as_str()).collect();
+        info!("Extra file rules: {}", specs.join(", "));
+        metadata.insert("rules".to_string(), specs.join(" "));
     }
 
-    if let Some(file_description) = &args.file_description {
-        write("stub_loader/file_description.txt", file_description)?;
-    } else if let Some(ref info) = input_version_info {
-        if let Some(ref file_description) = info.file_description {
-            write("stub_loader/file_description.txt", file_description)?;
-        } else {
-            let _ = std::fs::remove_file("stub_loader/file_description.txt");
-        }
+    // Display compression algorithm information
+    let params = codec.describe(&tuning);
+    if params.is_empty() {
+        info!("Using {} compression (level {})", codec.name(), args.level);
     } else {
-        let _ = std::fs::remove_file("stub_loader/file_description.txt");
+        let params_text: Vec<String> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        info!("Using {} compression (level {}, {})", codec.name(), args.level, params_text.join(", "));
+    }
+    metadata.insert("codec".to_string(), codec.name().to_string());
+    metadata.insert("level".to_string(), args.level.to_string());
+    metadata.insert("bcj".to_string(), (!args.no_bcj).to_string());
+    metadata.insert("solid".to_string(), args.solid.to_string());
+    for (key, value) in params {
+        metadata.insert(key.to_string(), value);
     }
 
-    if let Some(product_version) = &args.product_version {
-        write("stub_loader/product_version.txt", product_version)?;
-    } else if let Some(ref info) = input_version_info {
-        if let Some(ref product_version) = info.product_version {
-            write("stub_loader/product_version.txt", product_version)?;
-        } else {
-            let _ = std::fs::remove_file("stub_loader/product_version.txt");
-        }
+    // With --input-version-info, strings not given fall back to those of the input executable
+    let input_version_info = if args.input_version_info {
+        extract_version_info(&input).unwrap_or_default()
     } else {
-        let _ = std::fs::remove_file("stub_loader/product_version.txt");
+        VersionInfo::default()
+    };
+    let icon = match extract_icon(&input) {
+        Ok(icon) => Some(icon),
+        // Only Windows executables have icons to keep
+        Err(e) if !input_data.starts_with(b"MZ") => {
+            debug!("No icon read from {}: {}", input.display(), e);
+            None
+        }
+        Err(e) => {
+            report::warn(format!("no icon found in {} ({}), so the output gets the default icon", input.display(), e));
+            None
+        }
+    };
+    let stub = StubSettings {
+        gui: args.gui,
+        cleanup: args.cleanup,
+        icon,
+        product_name: args.product_name.clone().or(input_version_info.product_name),
+        company_name: args.company_name.clone().or(input_version_info.company_name),
+        file_description: args.file_description.clone().or(input_version_info.file_description),
+        product_version: args.product_version.clone().or(input_version_info.product_version),
+        file_version: args.file_version.clone().or(input_version_info.file_version),
+        copyright: args.copyright.clone().or(input_version_info.copyright),
+        // For the OriginalFilename field
+        original_filename: output.file_name().unwrap().to_string_lossy().to_string(),
+        require_admin: args.require_admin,
+        manifest: args.manifest,
+    };
+
+    let main_filter = if args.no_bcj { None } else { filter::detect(input_data) };
+    if let Some(f) = main_filter {
+        info!("Applying {} branch filter to {}", f.name(), input.display());
+    }
+    if args.parallel > 1 && codec.multithreaded() {
+        info!("Compressing {} on {} threads", input.display(), args.parallel);
     }
 
-    if let Some(file_version) = &args.file_version {
-        write("stub_loader/file_version.txt", file_version)?;
-    } else if let Some(ref info) = input_version_info {
-        if let Some(ref file_version) = info.file_version {
-            write("stub_loader/file_version.txt", file_version)?;
-        } else {
-            let _ = std::fs::remove_file("stub_loader/file_version.txt");
+    Ok(Some(Job {
+        args,
+        files,
+        codec,
+        tuning,
+        metadata,
+        main_filter,
+        source_date_epoch,
+        stub,
+        warnings: report::take_warnings(),
+        prepare_secs: start.elapsed().as_secs_f64(),
+    }))
+}
+
+/// Packs `job` with `stub`, checks the result against --max-output-size and
+/// --check-reproducible, then moves it into place. For --check-reproducible,
+/// `rebuilt` is a second, independent build of the stub to pack again with.
+fn run(job: &Job, stub: &[u8], rebuilt: Option<&[u8]>) -> Result<Outcome, PackError> {
+    let start = Instant::now();
+    let input_size = fs::metadata(job.input()).map_err(|e| PackError::input(job.input(), e))?.len();
+    let packed = pack(job, stub)?;
+
+    if let Some(limit) = job.args.max_output_size {
+        if packed.size > limit {
+            let breakdown = size_breakdown(packed.stub_len, &packed.main, &packed.extras, packed.size, limit);
+            return Err(PackError::check(job.output(), breakdown));
         }
-    } else {
-        let _ = std::fs::remove_file("stub_loader/file_version.txt");
     }
 
-    if let Some(copyright) = &args.copyright {
-        write("stub_loader/copyright.txt", copyright)?;
-    } else if let Some(ref info) = input_version_info {
-        if let Some(ref copyright) = info.copyright {
-            write("stub_loader/copyright.txt", copyright)?;
-        } else {
-            let _ = std::fs::remove_file("stub_loader/copyright.txt");
+    if let Some(rebuilt) = rebuilt {
+        info!("Packing again with the rebuilt stub to check that the output is reproducible...");
+        let again = pack(job, rebuilt)?;
+        let difference = first_difference(packed.file.path(), again.file.path()).map_err(|e| PackError::output(job.output(), e))?;
+        if let Some(offset) = difference {
+            let region = packed
+                .regions
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= offset)
+                .map(|(_, name)| name.as_str())
+                .unwrap_or("the stub loader");
+            return Err(PackError::check(
+                job.output(),
+                format!(
+                    "output is not reproducible: the two packs ({} and {} bytes) first differ at byte {}, in {}",
+                    packed.size, again.size, offset, region
+                ),
+            ));
         }
-    } else {
-        let _ = std::fs::remove_file("stub_loader/copyright.txt");
+        info!("Output is reproducible ({} bytes)", packed.size);
     }
 
-    // Write the output filename for OriginalFilename field
-    let output_filename = args.output.file_name().unwrap().to_string_lossy();
-    write("stub_loader/original_filename.txt", output_filename.as_ref())?;
+    let outcome = Outcome {
+        input_size,
+        main_size: packed.main.len,
+        size: packed.size,
+        report: report::build(job, &packed, job.prepare_secs + start.elapsed().as_secs_f64()),
+    };
+    packed
+        .file
+        .persist(job.output())
+        .map_err(|e| PackError::output(job.output(), e.error))?;
+    Ok(outcome)
+}
 
-    // Write admin flag
-    if args.require_admin {
-        let _ = std::fs::remove_file("stub_loader/require_admin.txt");
-        write("stub_loader/require_admin.txt", "true")?;
-    } else {
-        let _ = std::fs::remove_file("stub_loader/require_admin.txt");
+/// A packed executable in a temporary file next to the output, with the
+/// entries it was assembled from.
+struct Packed {
+    file: tempfile::NamedTempFile,
+    size: u64,
+    stub_len: usize,
+    main: SpilledEntry,
+    extras: Vec<SpilledEntry>,
+    /// Where each part of the file starts, for reporting differences
+    regions: Vec<(u64, String)>,
+    // Declared last so the entries are dropped before their directory
+    _spill: Spill,
+}
+
+/// Compresses the input and extra files and writes the packed executable.
+/// Extra files are stored sorted by path (see `extras::collect`), those in
+/// the solid block after the others, so identical inputs and flags give
+/// byte-identical output.
+fn pack(job: &Job, stub: &[u8]) -> Result<Packed, PackError> {
+    let &Job {
+        ref args,
+        ref files,
+        codec,
+        ref tuning,
+        ref metadata,
+        main_filter,
+        source_date_epoch,
+        ..
+    } = job;
+    let input = args.input.as_deref().unwrap();
+    let output = args.output.as_deref().unwrap();
+    let original_filename = output.file_name().unwrap().to_string_lossy().to_string();
+
+    let input_size = fs::metadata(input).map_err(|e| PackError::input(input, e))?.len();
+    let extras_size: u64 = files.iter().map(|file| file.size).sum();
+    let progress = Progress::files(
+        &format!("Packing {}", original_filename),
+        1 + files.len() as u64,
+        input_size + extras_size,
+    );
+
+    let main_threads = args.parallel.max(1) as u32;
+    let spill = Spill::new().map_err(|e| PackError::output(&std::env::temp_dir(), e))?;
+    let mut main_input = Reading(BufReader::new(File::open(input).map_err(|e| PackError::input(input, e))?));
+    let main = spill
+        .compress(original_filename, &mut progress.reader(&mut main_input), |input, output| {
+            codec::compress_stream(codec, args.level, tuning, main_threads, main_filter, input, output)
+        })
+        .map_err(|e| PackError::compressing(input, e, PackError::input))?;
+    progress.done(main.len);
+
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(args.parallel)
+        .build()
+        .unwrap();
+    let extras: Vec<SpilledEntry> = pool.install(|| {
+        extras::read_all(files, |file, input| {
+            let path = file.source.path();
+            if let Some(target) = &file.link {
+                let params = BTreeMap::from([("link".to_string(), container::link_param(target))]);
+                progress.done(0);
+                let entry = spill.compress(file.name.clone(), &mut std::io::empty(), |_, _| Ok(params));
+                return entry.map_err(|e| PackError::codec(&path, e));
+            }
+            let failed = |e| PackError::compressing(&path, e, PackError::extra);
+            let mut input = Reading(input);
+            let (filter, mut input) = filter::detect_stream(progress.reader(&mut input)).map_err(failed)?;
+            let filter = filter.filter(|_| !args.no_bcj);
+            let solid = args.solid && file.rule.is_none();
+            let entry = match file.rule {
+                Some(choice) => rules::compress(&spill, file.name.clone(), &mut input, choice, tuning, filter),
+                // Left uncompressed for the solid block below
+                None if solid => spill.compress(file.name.clone(), &mut input, |input, output| {
+                    codec::stage_solid(filter, input, output)
+                }),
+                None => spill.compress(file.name.clone(), &mut input, |input, output| {
+                    codec::compress_stream(codec, args.level, tuning, 1, filter, input, output)
+                }),
+            };
+            let mut entry = entry.map_err(failed)?;
+            if let Some(mode) = file.mode {
+                entry.params.insert("mode".to_string(), format!("{:o}", mode));
+            }
+            if solid {
+                entry.params.insert("solid".to_string(), entry.original.to_string());
+            } else {
+                progress.done(entry.len);
+            }
+            Ok(entry)
+        })
+    })?;
+    // The solid block goes after the other extras, so its files are adjacent
+    let (mut extras, mut solid): (Vec<_>, Vec<_>) = extras.into_iter().partition(|e| !e.params.contains_key("solid"));
+    if !solid.is_empty() {
+        spill
+            .solid(&mut solid, |input, output| {
+                codec::compress_stream(codec, args.level, tuning, main_threads, None, input, output)
+            })
+            .map_err(|e| PackError::codec(Path::new(&format!("the solid block of {} extra files", solid.len())), e))?;
+        for member in &solid {
+            progress.done(member.len);
+        }
+        extras.append(&mut solid);
     }
+    drop(progress);
 
-    // Write manifest flag
-    if args.manifest {
-        let _ = std::fs::remove_file("stub_loader/manifest.txt");
-        write("stub_loader/manifest.txt", "true")?;
-    } else {
-        let _ = std::fs::remove_file("stub_loader/manifest.txt");
-    }
-    let compressed_data = if args.brotli {
-        let mut out = vec![];
-        let mut encoder = CompressorWriter::new(&mut out, 4096, args.level, 22);
-        encoder.write_all(&input_data)?;
-        drop(encoder);
-        out
-    } else if args.zstd {
-        let mut out = vec![];
-        let mut encoder = Encoder::new(&mut out, args.level as i32)?;
-        encoder.write_all(&input_data)?;
-        encoder.finish()?;
-        out
-    } else {
-        let mut out = vec![];
-        let mut encoder = XzEncoder::new(&mut out, args.level);
-        encoder.write_all(&input_data)?;
-        encoder.finish()?;
-        out
+    let mut stub = stub.to_vec();
+    if let Some(secs) = source_date_epoch {
+        pe::set_timestamp(&mut stub, secs);
+    }
+
+    // Append marker + payload after the stub. The output is written under a
+    // temporary name next to it and only renamed into place once complete
+    let output_dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
-	
-	let mut extra_files = Arc::new(Mutex::new(vec![]));
-	
-	let ef = extra_files.clone();
-	if let Some(xtra) = &args.extra_dir {
-		if xtra.is_dir() {
-			//for entry in WalkDir::new(xtra.clone())
-			//	.into_iter()
-			
-			let pool = ThreadPoolBuilder::new()
-                    .num_threads(args.parallel)
-                    .build()
-                    .unwrap();
-
-			pool.install(|| {
-				WalkDir::new(xtra.clone())
-					.into_iter()
-					.filter_map(|e| e.ok())
-					.filter(|e| e.file_type().is_file())
-					.par_bridge()
-					.for_each(|entry| 
-				{
-					let path = entry.path();
-					let rel_path = path.strip_prefix(&xtra).unwrap().to_string_lossy().replace("\\", "/");
-					let data = fs::read(path).unwrap();
-
-					let compressed_data = if args.brotli {
-						let mut out = vec![];
-						let mut encoder = CompressorWriter::new(&mut out, 4096, args.level, 22);
-						encoder.write_all(&data).unwrap();
-						drop(encoder);
-						out
-					} else if args.zstd {
-						let mut out = vec![];
-						let mut encoder = Encoder::new(&mut out, args.level as i32).unwrap();
-						encoder.write_all(&data).unwrap();
-						encoder.finish().unwrap();
-						out
-					} else {
-						let mut out = vec![];
-						let mut encoder = XzEncoder::new(&mut out, args.level);
-						encoder.write_all(&data).unwrap();
-						encoder.finish().unwrap();
-						out
-					};
-
-					{
-						let ef2 = ef.clone();
-						let list = &mut *ef2.lock().unwrap();
-						list.push((rel_path, compressed_data));
-					}
-				});
-			});
-		}
-	}
-
-    let compression_ratio = (compressed_data.len() as f64 / input_data.len() as f64) * 100.0;
-    println!("Original size: {} bytes, Compressed: {} bytes ({:.2}% of original)",
-             input_data.len(), compressed_data.len(), compression_ratio);
-
-    // Embed compressed payload as base64
-    let payload = base64::engine::general_purpose::STANDARD.encode(&compressed_data);
-    let original_filename = args.output.file_name().unwrap().to_string_lossy();
-    let xfilenamex = base64::engine::general_purpose::STANDARD.encode(&original_filename.as_bytes());
-
-    // Read embedded stub EXE
-    let mut stub = stub::get_stub_exe(args.gui);
-    //let mut stub = stub::get_stub_exe();
-
-    // Append marker + base64 payload
-
-    stub.extend_from_slice(b"\n--XFILENAMEX--\n");
-    stub.extend_from_slice(xfilenamex.as_bytes());
-
-    // Append cleanup flag
-    if args.cleanup {
-        stub.extend_from_slice(b"\n--CLEANUP--\n");
-    }
-
-    // Append compressed file content
-    stub.extend_from_slice(b"\n--FILE-CONTENT--\n");
-    let payload_len_line = format!("{}\n", compressed_data.len());
-    stub.extend_from_slice(payload_len_line.as_bytes()); // Write the length as a single line
-    stub.extend_from_slice(&compressed_data);     
-
-	let ef3 = ef.clone();
-	{
-		let list = &*ef3.lock().unwrap();
-		for (filename, compressed_data) in list {
-			let encoded_name = STANDARD.encode(&filename);
-			stub.extend_from_slice(b"\n--EXTRA-FILE--\n");
-			stub.extend_from_slice(encoded_name.as_bytes());
-			stub.extend_from_slice(b"\n");
-			let len_line = format!("{}\n", compressed_data.len());
-			stub.extend_from_slice(len_line.as_bytes());
-			stub.extend_from_slice(&compressed_data);
-		}
-	}
-
-    fs::write(&args.output, stub)?;
-    println!("Compressed executable written to {:?}", args.output);
-    Ok(())
+    let mut partial_file = tempfile::Builder::new();
+    // Created like any new file (0666 less the umask), not owner-only
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        partial_file.permissions(fs::Permissions::from_mode(0o666));
+    }
+    let failed = |e| PackError::output(output, e);
+    let mut file = partial_file.tempfile_in(output_dir).map_err(failed)?;
+    let mut regions = vec![(0, "the stub loader".to_string())];
+    let mut writer =
+        Writer::new(BufWriter::new(file.as_file_mut()), &stub, &main.name, args.cleanup, metadata).map_err(failed)?;
+    regions.push((stub.len() as u64, "the container header or metadata".to_string()));
+    regions.push((writer.position(), format!("the main executable ({})", main.name)));
+    writer.main_entry(&main.params, main.len, &mut main.open().map_err(failed)?).map_err(failed)?;
+    for entry in &extras {
+        regions.push((writer.position(), format!("extra file {}", entry.name)));
+        writer
+            .extra_entry(&entry.name, &entry.params, entry.len, &mut entry.open().map_err(failed)?)
+            .map_err(failed)?;
+    }
+    let (_, size) = writer.finish().map_err(failed)?;
+
+    Ok(Packed {
+        file,
+        size,
+        stub_len: stub.len(),
+        main,
+        extras,
+        regions,
+        _spill: spill,
+    })
+}
+
+/// Offset of the first byte at which two files differ, or `None` if they are
+/// identical. One file ending early counts as a difference at its length.
+fn first_difference(a: &Path, b: &Path) -> std::io::Result<Option<u64>> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    let mut offset = 0u64;
+    loop {
+        let (chunk_a, chunk_b) = (a.fill_buf()?, b.fill_buf()?);
+        if chunk_a.is_empty() || chunk_b.is_empty() {
+            return Ok((chunk_a.len() != chunk_b.len()).then_some(offset));
+        }
+        let n = chunk_a.len().min(chunk_b.len());
+        if let Some(i) = (0..n).find(|&i| chunk_a[i] != chunk_b[i]) {
+            return Ok(Some(offset + i as u64));
+        }
+        a.consume(n);
+        b.consume(n);
+        offset += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_accepts_suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        assert_eq!(parse_size(" 2 G "), Ok(2 << 30));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn parse_size_rejects_bad_sizes() {
+        assert_eq!(parse_size("12T"), Err("Unknown size suffix 'T'".to_string()));
+        assert_eq!(parse_size("M"), Err("Invalid size 'M'".to_string()));
+        assert_eq!(parse_size(""), Err("Invalid size ''".to_string()));
+        assert_eq!(parse_size("-1"), Err("Invalid size '-1'".to_string()));
+        assert_eq!(parse_size("1.5G"), Err("Invalid size '1.5G'".to_string()));
+        // Overflows u64 once multiplied
+        assert!(parse_size("17179869184G").is_err());
+    }
 }
@@ -0,0 +1,636 @@
+use crate::codec;
+use crate::container::{Container, Entry};
+use crate::filter::Filter;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use execompress_codecs::{self as codecs, Tuning};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const PATCH_MAGIC: &[u8] = b"--EXECOMPRESS-PATCH--\n";
+const PATCH_VERSION: u32 = 4;
+
+/// How a single entry of the new container is reconstructed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    /// Bytes are identical to an entry of the old container
+    Copy,
+    /// zstd delta against an entry of the old container
+    Delta,
+    /// zstd delta of the decompressed contents against those of an entry of
+    /// the old container, compressed again as recorded in the `Recipe`
+    Repack,
+    /// zstd-compressed bytes with no reference
+    Full,
+}
+
+impl Op {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Op::Copy => "copy",
+            Op::Delta => "delta",
+            Op::Repack => "repack",
+            Op::Full => "full",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Op> {
+        match s {
+            "copy" => Some(Op::Copy),
+            "delta" => Some(Op::Delta),
+            "repack" => Some(Op::Repack),
+            "full" => Some(Op::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Which part of the container an entry or a reference points at.
+#[derive(Debug, Clone, PartialEq)]
+enum Slot {
+    Stub,
+    Main,
+    Extra(String),
+}
+
+impl Slot {
+    fn encode(&self) -> String {
+        match self {
+            Slot::Stub => "stub".to_string(),
+            Slot::Main => "main".to_string(),
+            Slot::Extra(name) => format!("extra:{}", STANDARD.encode(name.as_bytes())),
+        }
+    }
+
+    fn parse(s: &str) -> Option<Slot> {
+        match s {
+            "stub" => Some(Slot::Stub),
+            "main" => Some(Slot::Main),
+            _ => {
+                let encoded = s.strip_prefix("extra:")?;
+                let decoded = STANDARD.decode(encoded).ok()?;
+                Some(Slot::Extra(String::from_utf8(decoded).ok()?))
+            }
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Slot::Stub => "<stub>".to_string(),
+            Slot::Main => "<main>".to_string(),
+            Slot::Extra(name) => name.clone(),
+        }
+    }
+}
+
+/// The compression settings that, with the codec and filter recorded in its
+/// header, turn an entry's contents back into exactly its stored bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Recipe {
+    level: u32,
+    threads: u32,
+}
+
+impl Recipe {
+    fn encode(&self) -> String {
+        format!("level={},threads={}", self.level, self.threads)
+    }
+
+    fn parse(s: &str) -> Option<Recipe> {
+        let (level, threads) = s.split_once(',')?;
+        Some(Recipe {
+            level: level.strip_prefix("level=")?.parse().ok()?,
+            threads: threads.strip_prefix("threads=")?.parse().ok()?,
+        })
+    }
+}
+
+fn slot_entry<'a>(container: &'a Container, slot: &Slot) -> Option<&'a Entry> {
+    match slot {
+        Slot::Stub => None,
+        Slot::Main => Some(&container.main),
+        Slot::Extra(name) => container.extras.iter().find(|e| &e.name == name),
+    }
+}
+
+fn slot_data<'a>(container: &'a Container, slot: &Slot) -> Option<&'a [u8]> {
+    match slot {
+        Slot::Stub => Some(&container.stub),
+        _ => slot_entry(container, slot).map(|e| e.data.as_slice()),
+    }
+}
+
+/// Per-entry header parameters (codec ID, filter) as base64 JSON, or "-".
+fn encode_params(container: &Container, slot: &Slot) -> String {
+    match slot_entry(container, slot) {
+        Some(entry) if !entry.params.is_empty() => STANDARD.encode(serde_json::to_vec(&entry.params).unwrap()),
+        _ => "-".to_string(),
+    }
+}
+
+fn decode_params(s: &str) -> io::Result<BTreeMap<String, String>> {
+    if s == "-" {
+        return Ok(BTreeMap::new());
+    }
+    STANDARD
+        .decode(s)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| invalid("Invalid entry parameters in patch"))
+}
+
+fn slots(container: &Container) -> Vec<(Slot, &[u8])> {
+    let mut list = vec![
+        (Slot::Stub, container.stub.as_slice()),
+        (Slot::Main, container.main.data.as_slice()),
+    ];
+    for entry in &container.extras {
+        list.push((Slot::Extra(entry.name.clone()), entry.data.as_slice()));
+    }
+    list
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn window_log_for(len: usize) -> u32 {
+    let bits = usize::BITS - len.max(1).leading_zeros();
+    bits.clamp(10, codecs::ZSTD_WINDOW_LOG_MAX)
+}
+
+fn encode_delta(reference: &[u8], data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut encoder = zstd::stream::Encoder::with_ref_prefix(&mut out, level, reference)?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log_for(reference.len() + data.len()))?;
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(out)
+}
+
+fn decode_delta(reference: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = zstd::stream::Decoder::with_ref_prefix(patch, reference)?;
+    decoder.window_log_max(codecs::ZSTD_WINDOW_LOG_MAX)?;
+    let mut out = vec![];
+    decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fn encode_full(data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut encoder = zstd::stream::Encoder::new(&mut out, level)?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log_for(data.len()))?;
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(out)
+}
+
+fn decode_full(patch: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = zstd::stream::Decoder::new(patch)?;
+    decoder.window_log_max(codecs::ZSTD_WINDOW_LOG_MAX)?;
+    let mut out = vec![];
+    decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Codec tuning recorded in the container metadata by `Codec::describe`.
+fn tuning(metadata: &BTreeMap<String, String>) -> Tuning {
+    let number = |key: &str| metadata.get(key).and_then(|v| v.parse().ok());
+    let flag = |key: &str| metadata.get(key).is_some_and(|v| v == "true");
+    Tuning {
+        xz_extreme: flag("xz_extreme"),
+        xz_dict_size: number("xz_dict_size"),
+        zstd_window_log: number("zstd_window_log"),
+        brotli_lgwin: number("brotli_lgwin"),
+        brotli_large_window: flag("brotli_large_window"),
+    }
+}
+
+/// What `recompress` takes to give an entry's stored bytes: its contents,
+/// or the filtered files of a solid block it starts.
+fn contents(entry: &Entry) -> io::Result<Vec<u8>> {
+    match entry.solid() {
+        Some(_) => entry.decode_stream(),
+        None => entry.decompress(),
+    }
+}
+
+/// Compresses `contents` the way `entry` records it was packed, with the
+/// tuning of the container it belongs to.
+fn recompress(entry: &Entry, contents: &[u8], recipe: Recipe, metadata: &BTreeMap<String, String>) -> io::Result<Vec<u8>> {
+    let codec = codecs::by_name(entry.codec())
+        .ok_or_else(|| invalid(format!("{} uses a codec that is not enabled in this build", entry.name)))?;
+    let filter = match entry.filter() {
+        // Reversed per file, outside the block's stream
+        _ if entry.solid().is_some() => None,
+        None => None,
+        // Chained into the xz stream by compress_entry
+        Some("xz-x86") => Some(Filter::X86),
+        Some(name) => {
+            Some(Filter::from_name(name).ok_or_else(|| invalid(format!("Unknown filter '{}' for {}", name, entry.name)))?)
+        }
+    };
+    let tuning = tuning(metadata);
+    let packed = codec::compress_entry(entry.name.clone(), codec, recipe.level, &tuning, recipe.threads, filter, contents)?;
+    Ok(packed.data)
+}
+
+/// A delta between the contents of two entries, rather than their compressed
+/// bytes, which a small change rewrites almost entirely. Only used when
+/// compressing the new contents again reproduces the stored bytes, trying
+/// the single-threaded and the multithreaded encoder.
+fn repack_delta(
+    old: &Entry,
+    new: &Entry,
+    metadata: &BTreeMap<String, String>,
+    level: i32,
+) -> io::Result<Option<(Recipe, Vec<u8>)>> {
+    // apply_patch has no stored bytes to detect an unrecorded codec from
+    if old.link().is_some() || new.link().is_some() || !new.params.contains_key("codec") {
+        return Ok(None);
+    }
+    let Some(codec) = codecs::by_name(new.codec()) else {
+        return Ok(None);
+    };
+    let Some(entry_level) = new.params.get("level").or(metadata.get("level")).and_then(|l| l.parse().ok()) else {
+        return Ok(None);
+    };
+    let (Ok(old_contents), Ok(contents)) = (contents(old), contents(new)) else {
+        return Ok(None);
+    };
+    let threads: &[u32] = if codec.multithreaded() { &[1, 2] } else { &[1] };
+    for &threads in threads {
+        let recipe = Recipe {
+            level: entry_level,
+            threads,
+        };
+        if recompress(new, &contents, recipe, metadata).is_ok_and(|data| data == new.data) {
+            return Ok(Some((recipe, encode_delta(&old_contents, &contents, level)?)));
+        }
+    }
+    Ok(None)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Creates a patch turning packed executable `old_path` into `new_path`.
+///
+/// Every entry of the new container (stub, main executable, extra files) is
+/// either referenced from the old container when its bytes are unchanged, or
+/// stored as a zstd delta against the old entry of the same name. Compressed
+/// entries are diffed by their contents when `apply_patch` can compress them
+/// back to the same bytes, and by their stored bytes otherwise.
+pub fn diff_patch(old_path: &Path, new_path: &Path, output: &Path, level: i32) -> io::Result<()> {
+    let old_bytes = fs::read(old_path)?;
+    let new_bytes = fs::read(new_path)?;
+    let old = Container::parse(&old_bytes)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", old_path.display(), e)))?;
+    let new = Container::parse(&new_bytes)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", new_path.display(), e)))?;
+
+    let old_by_hash: HashMap<String, Slot> = slots(&old)
+        .into_iter()
+        .map(|(slot, data)| (sha256_hex(data), slot))
+        .collect();
+
+    let new_slots = slots(&new);
+    let mut out = vec![];
+    out.extend_from_slice(PATCH_MAGIC);
+    out.extend_from_slice(format!("version {}\n", PATCH_VERSION).as_bytes());
+    out.extend_from_slice(format!("old {}\n", sha256_hex(&old_bytes)).as_bytes());
+    out.extend_from_slice(format!("new {}\n", sha256_hex(&new_bytes)).as_bytes());
+    out.extend_from_slice(format!("name {}\n", STANDARD.encode(new.main.name.as_bytes())).as_bytes());
+    out.extend_from_slice(format!("cleanup {}\n", new.cleanup).as_bytes());
+    let metadata = serde_json::to_vec(&new.metadata).unwrap();
+    out.extend_from_slice(format!("metadata {}\n", STANDARD.encode(metadata)).as_bytes());
+    out.extend_from_slice(format!("entries {}\n", new_slots.len()).as_bytes());
+
+    let (mut copied, mut delta, mut full) = (0, 0, 0);
+    for (slot, data) in &new_slots {
+        let hash = sha256_hex(data);
+        let (op, source, payload, recipe) = if let Some(source) = old_by_hash.get(&hash) {
+            copied += 1;
+            (Op::Copy, Some(source.clone()), vec![], None)
+        } else if let Some(reference) = slot_data(&old, slot) {
+            delta += 1;
+            let stored = encode_delta(reference, data, level)?;
+            let repacked = match (slot_entry(&old, slot), slot_entry(&new, slot)) {
+                (Some(old_entry), Some(new_entry)) => repack_delta(old_entry, new_entry, &new.metadata, level)?,
+                _ => None,
+            };
+            match repacked {
+                Some((recipe, payload)) if payload.len() < stored.len() => {
+                    (Op::Repack, Some(slot.clone()), payload, Some(recipe))
+                }
+                _ => (Op::Delta, Some(slot.clone()), stored, None),
+            }
+        } else {
+            full += 1;
+            (Op::Full, None, encode_full(data, level)?, None)
+        };
+
+        let source = source.map(|s| s.encode()).unwrap_or_else(|| "-".to_string());
+        let recipe = recipe.map(|r| r.encode()).unwrap_or_else(|| "-".to_string());
+        let header = format!(
+            "{} {} {} {} {} {} {} {}\n",
+            slot.encode(),
+            op.as_str(),
+            source,
+            data.len(),
+            hash,
+            payload.len(),
+            encode_params(&new, slot),
+            recipe
+        );
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(&payload);
+        info!("{:<6} {} ({} -> {} bytes)", op.as_str(), slot.label(), data.len(), payload.len());
+    }
+
+    fs::write(output, &out)?;
+    info!(
+        "Patch written to {:?}: {} bytes ({} unchanged, {} delta, {} new entries, target {} bytes)",
+        output,
+        out.len(),
+        copied,
+        delta,
+        full,
+        new_bytes.len()
+    );
+    Ok(())
+}
+
+/// Applies a patch created by [`diff_patch`] to `old_path`, writing the
+/// reconstructed packed executable to `output`. Every entry and the final
+/// file are checked against the hashes recorded from the new container.
+pub fn apply_patch(old_path: &Path, patch_path: &Path, output: &Path) -> io::Result<()> {
+    let old_bytes = fs::read(old_path)?;
+    let patch = fs::read(patch_path)?;
+
+    if !patch.starts_with(PATCH_MAGIC) {
+        return Err(invalid(format!("{}: not an execompress patch", patch_path.display())));
+    }
+    let mut pos = PATCH_MAGIC.len();
+
+    let version = header_value(&patch, &mut pos, "version")?;
+    if version != PATCH_VERSION.to_string() {
+        return Err(invalid(format!("Unsupported patch version {}", version)));
+    }
+    let old_hash = header_value(&patch, &mut pos, "old")?;
+    let new_hash = header_value(&patch, &mut pos, "new")?;
+    let name = header_value(&patch, &mut pos, "name")?;
+    let cleanup = header_value(&patch, &mut pos, "cleanup")? == "true";
+    let metadata = STANDARD
+        .decode(header_value(&patch, &mut pos, "metadata")?)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| invalid("Invalid metadata in patch"))?;
+    let count = header_value(&patch, &mut pos, "entries")?
+        .parse::<usize>()
+        .map_err(|_| invalid("Invalid entry count in patch"))?;
+
+    if sha256_hex(&old_bytes) != old_hash {
+        return Err(invalid(format!(
+            "{} does not match the base executable this patch was created from",
+            old_path.display()
+        )));
+    }
+    let old = Container::parse(&old_bytes)?;
+
+    let name = STANDARD
+        .decode(&name)
+        .ok()
+        .and_then(|n| String::from_utf8(n).ok())
+        .ok_or_else(|| invalid("Invalid executable name in patch"))?;
+    let mut new = Container {
+        stub: vec![],
+        cleanup,
+        metadata,
+        main: Entry::new(name, vec![]),
+        extras: vec![],
+    };
+
+    for _ in 0..count {
+        let line = read_line(&patch, &mut pos)?;
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 8 {
+            return Err(invalid(format!("Malformed patch entry: {}", line)));
+        }
+        let slot = Slot::parse(fields[0]).ok_or_else(|| invalid(format!("Invalid entry: {}", fields[0])))?;
+        let op = Op::parse(fields[1]).ok_or_else(|| invalid(format!("Invalid operation: {}", fields[1])))?;
+        let source = if fields[2] == "-" { None } else { Slot::parse(fields[2]) };
+        let expected_len = fields[3].parse::<usize>().map_err(|_| invalid("Invalid entry size"))?;
+        let expected_hash = fields[4];
+        let payload_len = fields[5].parse::<usize>().map_err(|_| invalid("Invalid payload size"))?;
+        let params = decode_params(fields[6])?;
+        let recipe = match fields[7] {
+            "-" => None,
+            recipe => Some(Recipe::parse(recipe).ok_or_else(|| invalid(format!("Invalid recipe: {}", recipe)))?),
+        };
+
+        let payload = pos
+            .checked_add(payload_len)
+            .and_then(|end| patch.get(pos..end))
+            .ok_or_else(|| invalid("Patch file is truncated"))?;
+        pos += payload_len;
+
+        let reference = || {
+            source
+                .as_ref()
+                .and_then(|s| slot_data(&old, s))
+                .ok_or_else(|| invalid(format!("Base executable has no entry for {}", slot.label())))
+        };
+        let data = match op {
+            Op::Copy => reference()?.to_vec(),
+            Op::Delta => decode_delta(reference()?, payload)?,
+            Op::Repack => {
+                let old_entry = source
+                    .as_ref()
+                    .and_then(|s| slot_entry(&old, s))
+                    .ok_or_else(|| invalid(format!("Base executable has no entry for {}", slot.label())))?;
+                let recipe = recipe.ok_or_else(|| invalid(format!("Missing recipe for {}", slot.label())))?;
+                let contents = decode_delta(&contents(old_entry)?, payload)?;
+                let entry = Entry {
+                    name: slot.label(),
+                    data: vec![],
+                    params: params.clone(),
+                };
+                recompress(&entry, &contents, recipe, &new.metadata)?
+            }
+            Op::Full => decode_full(payload)?,
+        };
+
+        if data.len() != expected_len || sha256_hex(&data) != expected_hash {
+            return Err(invalid(format!("Hash mismatch for {} after patching", slot.label())));
+        }
+
+        match slot {
+            Slot::Stub => new.stub = data,
+            Slot::Main => {
+                new.main.data = data;
+                new.main.params = params;
+            }
+            Slot::Extra(name) => new.extras.push(Entry { name, data, params }),
+        }
+    }
+
+    let bytes = new.to_bytes();
+    if sha256_hex(&bytes) != new_hash {
+        return Err(invalid("Reconstructed executable does not match the patch target hash"));
+    }
+
+    fs::write(output, &bytes)?;
+    info!("Patched executable written to {:?} ({} bytes, sha256 {})", output, bytes.len(), new_hash);
+    Ok(())
+}
+
+fn read_line(buffer: &[u8], pos: &mut usize) -> io::Result<String> {
+    let len = buffer[*pos..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| invalid("Patch file is truncated"))?;
+    let line = String::from_utf8_lossy(&buffer[*pos..*pos + len]).to_string();
+    *pos += len + 1;
+    Ok(line)
+}
+
+fn header_value(buffer: &[u8], pos: &mut usize, key: &str) -> io::Result<String> {
+    let line = read_line(buffer, pos)?;
+    match line.split_once(' ') {
+        Some((k, v)) if k == key => Ok(v.to_string()),
+        _ => Err(invalid(format!("Expected '{}' in patch header", key))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text-like contents that compress well, varied by `seed`.
+    fn contents(len: usize, seed: u32) -> Vec<u8> {
+        (0..len as u32).map(|i| b"abcdefgh\n"[((i / 7) ^ seed) as usize % 9]).collect()
+    }
+
+    fn entry(name: &str, data: &[u8]) -> Entry {
+        let zstd = codecs::by_name("zstd").unwrap();
+        codec::compress_entry(name.to_string(), zstd, 9, &Tuning::default(), 1, None, data).unwrap()
+    }
+
+    fn container(main: &[u8], extras: Vec<Entry>) -> Container {
+        Container {
+            stub: b"MZ stub".to_vec(),
+            cleanup: false,
+            metadata: BTreeMap::from([("level".to_string(), "9".to_string())]),
+            main: entry("app.exe", main),
+            extras,
+        }
+    }
+
+    struct Files {
+        dir: tempfile::TempDir,
+    }
+
+    impl Files {
+        fn new() -> Files {
+            Files {
+                dir: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        fn path(&self, name: &str) -> std::path::PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn write(&self, name: &str, container: &Container) -> std::path::PathBuf {
+            let path = self.path(name);
+            fs::write(&path, container.to_bytes()).unwrap();
+            path
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let files = Files::new();
+        let main = contents(200_000, 1);
+        let mut changed_main = main.clone();
+        changed_main[100_000..100_010].copy_from_slice(b"0123456789");
+        let old = container(
+            &main,
+            vec![
+                entry("changed.txt", &contents(50_000, 2)),
+                entry("removed.txt", &contents(1_000, 3)),
+                entry("same.txt", &contents(10_000, 4)),
+            ],
+        );
+        let new = container(
+            &changed_main,
+            vec![
+                entry("added.txt", &contents(5_000, 5)),
+                entry("changed.txt", &contents(50_100, 2)),
+                entry("same.txt", &contents(10_000, 4)),
+            ],
+        );
+        let (old_path, new_path) = (files.write("old", &old), files.write("new", &new));
+        let (patch_path, output) = (files.path("patch"), files.path("out"));
+
+        diff_patch(&old_path, &new_path, &patch_path, 19).unwrap();
+        apply_patch(&old_path, &patch_path, &output).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&new_path).unwrap());
+
+        // The changed main executable is diffed by its contents, not its compressed bytes
+        let patch = fs::read(&patch_path).unwrap();
+        let header = format!("{} {} ", Slot::Main.encode(), Op::Repack.as_str());
+        assert!(find(&patch, header.as_bytes()), "main entry was not repacked");
+    }
+
+    #[test]
+    fn rejects_other_base_and_truncated_patch() {
+        let files = Files::new();
+        let old = files.write("old", &container(&contents(20_000, 1), vec![]));
+        let new = files.write("new", &container(&contents(20_000, 2), vec![]));
+        let other = files.write("other", &container(&contents(20_000, 3), vec![]));
+        let patch = files.path("patch");
+        diff_patch(&old, &new, &patch, 3).unwrap();
+
+        let e = apply_patch(&other, &patch, &files.path("out")).unwrap_err();
+        assert!(e.to_string().contains("does not match the base executable"), "{}", e);
+
+        let bytes = fs::read(&patch).unwrap();
+        fs::write(&patch, &bytes[..bytes.len() - 1]).unwrap();
+        let e = apply_patch(&old, &patch, &files.path("out")).unwrap_err();
+        assert_eq!(e.to_string(), "Patch file is truncated");
+
+        // A payload size that overflows the offset is truncation too, not a panic
+        let text = String::from_utf8_lossy(&bytes);
+        let start = text.find("\nstub ").unwrap() + 1;
+        let end = start + text[start..].find('\n').unwrap();
+        let mut fields: Vec<&str> = text[start..end].split(' ').collect();
+        let huge = usize::MAX.to_string();
+        fields[5] = &huge;
+        let mut overflowing = bytes[..start].to_vec();
+        overflowing.extend_from_slice(fields.join(" ").as_bytes());
+        overflowing.extend_from_slice(&bytes[end..]);
+        fs::write(&patch, overflowing).unwrap();
+        let e = apply_patch(&old, &patch, &files.path("out")).unwrap_err();
+        assert_eq!(e.to_string(), "Patch file is truncated");
+        assert!(!files.path("out").exists());
+    }
+
+    #[test]
+    fn recipe_round_trip() {
+        let recipe = Recipe { level: 19, threads: 2 };
+        assert_eq!(Recipe::parse(&recipe.encode()), Some(recipe));
+        assert_eq!(Recipe::parse("level=19"), None);
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+}
@@ -0,0 +1,187 @@
+// Minimal in-memory PE parsing shared by the inspection commands
+const PE_SIGNATURE: u32 = 0x00004550; // "PE\0\0"
+const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D; // "MZ"
+
+pub const RT_VERSION: u32 = 16;
+pub const RT_MANIFEST: u32 = 24;
+
+pub const IMAGE_SUBSYSTEM_WINDOWS_GUI: u16 = 2;
+
+#[derive(Debug)]
+struct Section {
+    virtual_address: u32,
+    virtual_size: u32,
+    pointer_to_raw_data: u32,
+    size_of_raw_data: u32,
+}
+
+pub struct PeFile<'a> {
+    data: &'a [u8],
+    pub machine: u16,
+    pub subsystem: u16,
+    resource_rva: u32,
+    sections: Vec<Section>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+impl<'a> PeFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if u16_at(data, 0)? != IMAGE_DOS_SIGNATURE {
+            return None;
+        }
+        let pe_offset = u32_at(data, 0x3C)? as usize;
+        if u32_at(data, pe_offset)? != PE_SIGNATURE {
+            return None;
+        }
+
+        // COFF header follows the signature
+        let coff = pe_offset + 4;
+        let machine = u16_at(data, coff)?;
+        let number_of_sections = u16_at(data, coff + 2)? as usize;
+        let optional_header_size = u16_at(data, coff + 16)? as usize;
+
+        let optional = coff + 20;
+        let data_directory_offset = match u16_at(data, optional)? {
+            0x10b => 96,  // PE32
+            0x20b => 112, // PE32+
+            _ => return None,
+        };
+        let subsystem = u16_at(data, optional + 68)?;
+        let resource_rva = u32_at(data, optional + data_directory_offset + 2 * 8).unwrap_or(0);
+
+        let mut sections = vec![];
+        let section_table = optional + optional_header_size;
+        for i in 0..number_of_sections {
+            let base = section_table + i * 40;
+            sections.push(Section {
+                virtual_size: u32_at(data, base + 8)?,
+                virtual_address: u32_at(data, base + 12)?,
+                size_of_raw_data: u32_at(data, base + 16)?,
+                pointer_to_raw_data: u32_at(data, base + 20)?,
+            });
+        }
+
+        Some(PeFile {
+            data,
+            machine,
+            subsystem,
+            resource_rva,
+            sections,
+        })
+    }
+
+    fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        self.sections.iter().find_map(|s| {
+            // Section fields come from the file, so a malformed one may overflow
+            let size = s.virtual_size.max(s.size_of_raw_data);
+            if rva >= s.virtual_address && rva < s.virtual_address.checked_add(size)? {
+                Some((rva - s.virtual_address).checked_add(s.pointer_to_raw_data)? as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the data of the first resource of the given type
+    /// (first name, first language).
+    pub fn find_resource(&self, resource_type: u32) -> Option<&'a [u8]> {
+        if self.resource_rva == 0 {
+            return None;
+        }
+        let root = self.rva_to_offset(self.resource_rva)?;
+
+        let type_dir = self.find_directory_entry(root, 0, Some(resource_type))?;
+        let name_dir = self.find_directory_entry(root, type_dir, None)?;
+        let data_entry = self.find_directory_entry(root, name_dir, None)?;
+
+        let data_rva = u32_at(self.data, root + data_entry as usize)?;
+        let size = u32_at(self.data, root + data_entry as usize + 4)? as usize;
+        let offset = self.rva_to_offset(data_rva)?;
+        self.data.get(offset..offset + size)
+    }
+
+    /// Looks up an entry in the resource directory at `dir` (relative to the
+    /// resource root). `id` of `None` takes the first entry.
+    fn find_directory_entry(&self, root: usize, dir: u32, id: Option<u32>) -> Option<u32> {
+        let base = root + dir as usize;
+        let count = u16_at(self.data, base + 12)? as usize + u16_at(self.data, base + 14)? as usize;
+        for i in 0..count {
+            let entry = base + 16 + i * 8;
+            let name_or_id = u32_at(self.data, entry)?;
+            let offset = u32_at(self.data, entry + 4)?;
+            if id.is_none() || id == Some(name_or_id) {
+                return Some(offset & 0x7FFFFFFF);
+            }
+        }
+        None
+    }
+}
+
+/// Overwrites the link time in the COFF header of a PE image; other data
+/// is left alone.
+pub fn set_timestamp(data: &mut [u8], secs: u32) {
+    if PeFile::parse(data).is_none() {
+        return;
+    }
+    // TimeDateStamp follows the signature, machine and section count
+    let offset = u32_at(data, 0x3C).unwrap() as usize + 8;
+    data[offset..offset + 4].copy_from_slice(&secs.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_sections(sections: Vec<Section>) -> PeFile<'static> {
+        PeFile {
+            data: &[],
+            machine: 0,
+            subsystem: 0,
+            resource_rva: 0,
+            sections,
+        }
+    }
+
+    #[test]
+    fn rva_to_offset_maps_into_sections() {
+        let pe = with_sections(vec![Section {
+            virtual_address: 0x1000,
+            virtual_size: 0x200,
+            pointer_to_raw_data: 0x400,
+            size_of_raw_data: 0x100,
+        }]);
+        assert_eq!(pe.rva_to_offset(0x1000), Some(0x400));
+        assert_eq!(pe.rva_to_offset(0x11FF), Some(0x5FF));
+        assert_eq!(pe.rva_to_offset(0x1200), None);
+        assert_eq!(pe.rva_to_offset(0xFFF), None);
+    }
+
+    #[test]
+    fn rva_to_offset_rejects_overflowing_sections() {
+        let pe = with_sections(vec![
+            Section {
+                virtual_address: 0xFFFF_F000,
+                virtual_size: 0x2000,
+                pointer_to_raw_data: 0,
+                size_of_raw_data: 0,
+            },
+            Section {
+                virtual_address: 0x1000,
+                virtual_size: 0x1000,
+                pointer_to_raw_data: 0xFFFF_FF00,
+                size_of_raw_data: 0,
+            },
+        ]);
+        assert_eq!(pe.rva_to_offset(0xFFFF_F800), None);
+        assert_eq!(pe.rva_to_offset(0x1800), None);
+    }
+}
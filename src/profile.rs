@@ -0,0 +1,103 @@
+use crate::config::Config;
+use serde::Deserialize;
+use std::io;
+
+pub const BUILTIN: &[&str] = &["fastest", "fast", "balanced", "small", "smallest"];
+
+/// A named set of pack settings. Unset fields leave the command line
+/// defaults alone.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub codec: Option<String>,
+    pub level: Option<u32>,
+    /// Apply the x86/ARM64 branch filter
+    pub bcj: Option<bool>,
+    /// Compress the extra files as one stream
+    pub solid: Option<bool>,
+    pub xz_extreme: Option<bool>,
+    /// Bytes, with an optional K/M/G suffix
+    pub xz_dict_size: Option<String>,
+    pub zstd_long: Option<u32>,
+    pub brotli_lgwin: Option<u32>,
+    pub brotli_large_window: Option<bool>,
+}
+
+impl Profile {
+    /// Fills every field not set in `self` from `base`.
+    fn or(self, base: Profile) -> Profile {
+        Profile {
+            codec: self.codec.or(base.codec),
+            level: self.level.or(base.level),
+            bcj: self.bcj.or(base.bcj),
+            solid: self.solid.or(base.solid),
+            xz_extreme: self.xz_extreme.or(base.xz_extreme),
+            xz_dict_size: self.xz_dict_size.or(base.xz_dict_size),
+            zstd_long: self.zstd_long.or(base.zstd_long),
+            brotli_lgwin: self.brotli_lgwin.or(base.brotli_lgwin),
+            brotli_large_window: self.brotli_large_window.or(base.brotli_large_window),
+        }
+    }
+}
+
+fn builtin(name: &str) -> Option<Profile> {
+    let profile = |codec: &str, level: u32| Profile {
+        codec: Some(codec.to_string()),
+        level: Some(level),
+        bcj: Some(true),
+        solid: Some(false),
+        ..Profile::default()
+    };
+    let profile = match name {
+        // Decompression speed first: the app starts almost as fast as unpacked
+        "fastest" => profile("lz4", 1),
+        "fast" => profile("zstd", 3),
+        // Long-range zstd: near xz sizes with much faster decompression
+        "balanced" => Profile {
+            zstd_long: Some(27),
+            ..profile("zstd", 19)
+        },
+        // Solid: the extras share one xz window, so files alike compress together
+        "small" => Profile {
+            solid: Some(true),
+            xz_dict_size: Some("64M".to_string()),
+            ..profile("lzma", 9)
+        },
+        "smallest" => Profile {
+            solid: Some(true),
+            xz_extreme: Some(true),
+            xz_dict_size: Some("128M".to_string()),
+            ..profile("lzma", 9)
+        },
+        _ => return None,
+    };
+    Some(profile)
+}
+
+/// Looks up `name` in the config file and the built-in profiles. A config
+/// profile with a built-in name only needs to list the fields it changes.
+pub fn resolve(name: &str, config: &Config) -> io::Result<Profile> {
+    let profile = match (config.profile.get(name).cloned(), builtin(name)) {
+        (Some(custom), Some(base)) => custom.or(base),
+        (Some(custom), None) => custom,
+        (None, Some(base)) => base,
+        (None, None) => {
+            let mut known: Vec<String> = BUILTIN.iter().map(|s| s.to_string()).collect();
+            known.extend(config.profile.keys().filter(|k| !BUILTIN.contains(&k.as_str())).cloned());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown profile '{}'. Available profiles: {}", name, known.join(", ")),
+            ));
+        }
+    };
+
+    if let Some(codec) = &profile.codec {
+        if codec != "auto" && execompress_codecs::by_name(codec).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Profile '{}' uses codec '{}', which is not enabled in this build", name, codec),
+            ));
+        }
+    }
+    Ok(profile)
+}
@@ -0,0 +1,178 @@
+// Progress of the slow phases of a pack: building the stub and compressing.
+// On a terminal it is drawn as a bar on stderr; otherwise a plain line is
+// logged every LOG_INTERVAL, so CI logs show that a long pack is still going.
+// Either is hidden by -q.
+use crate::logging;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{info, Level};
+use std::io::{self, IsTerminal, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const DRAW_INTERVAL: Duration = Duration::from_millis(100);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The bars of packs running side by side in `batch` share the terminal.
+fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+/// Runs `f`, which prints to the console, with the bars hidden meanwhile.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    bars().suspend(f)
+}
+
+#[derive(Default)]
+struct Counters {
+    files: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Files and bytes to compress.
+#[derive(Clone, Copy)]
+struct Totals {
+    files: u64,
+    bytes: u64,
+}
+
+/// A progress display, shown until it is dropped.
+pub struct Progress {
+    counters: Arc<Counters>,
+    stop: Option<Sender<()>>,
+    ticker: Option<JoinHandle<()>>,
+}
+
+/// Passes reads through, counting the bytes read.
+struct Counted<'a> {
+    inner: &'a mut dyn Read,
+    bytes: &'a AtomicU64,
+}
+
+impl Read for Counted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// A duration as `1h02m`, `3m05s` or `42s`.
+fn duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn status(counters: &Counters, totals: Option<Totals>, elapsed: Duration) -> String {
+    let Some(totals) = totals else {
+        return format!("{} elapsed", duration(elapsed.as_secs()));
+    };
+    let bytes_in = counters.bytes_in.load(Ordering::Relaxed);
+    let rate = bytes_in as f64 / elapsed.as_secs_f64().max(0.001);
+    let eta = if bytes_in == 0 {
+        "ETA -".to_string()
+    } else {
+        format!("ETA {}", duration((totals.bytes.saturating_sub(bytes_in) as f64 / rate) as u64))
+    };
+    format!(
+        "{}/{} files, {}/{} in, {} out, {}/s, {}",
+        counters.files.load(Ordering::Relaxed),
+        totals.files,
+        HumanBytes(bytes_in),
+        HumanBytes(totals.bytes),
+        HumanBytes(counters.bytes_out.load(Ordering::Relaxed)),
+        HumanBytes(rate as u64),
+        eta
+    )
+}
+
+impl Progress {
+    /// Progress of a step with no measurable amount of work, such as the stub build.
+    pub fn task(label: &str) -> Progress {
+        Progress::start(label, None)
+    }
+
+    /// Progress of compressing `files` files holding `bytes` bytes in total.
+    pub fn files(label: &str, files: u64, bytes: u64) -> Progress {
+        Progress::start(label, Some(Totals { files, bytes }))
+    }
+
+    fn start(label: &str, totals: Option<Totals>) -> Progress {
+        let counters = Arc::new(Counters::default());
+        if !logging::shows(Level::Info) {
+            return Progress {
+                counters,
+                stop: None,
+                ticker: None,
+            };
+        }
+        let bar = io::stderr().is_terminal().then(|| {
+            let (bar, template) = match totals {
+                Some(totals) => (ProgressBar::new(totals.bytes), "{spinner} {prefix} [{bar:30}] {msg}"),
+                None => (ProgressBar::new_spinner(), "{spinner} {prefix} {msg}"),
+            };
+            let bar = bars().add(bar);
+            bar.set_style(ProgressStyle::with_template(template).unwrap().progress_chars("=> "));
+            bar.set_prefix(label.to_string());
+            bar
+        });
+        let (stop, stopped) = mpsc::channel();
+        let label = label.to_string();
+        let ticker_counters = Arc::clone(&counters);
+        let ticker = thread::spawn(move || {
+            let start = Instant::now();
+            let interval = if bar.is_some() { DRAW_INTERVAL } else { LOG_INTERVAL };
+            // Runs until the sender is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let status = status(&ticker_counters, totals, start.elapsed());
+                match &bar {
+                    Some(bar) => {
+                        bar.set_position(ticker_counters.bytes_in.load(Ordering::Relaxed));
+                        bar.set_message(status);
+                        bar.tick();
+                    }
+                    None => info!("{}: {}", label, status),
+                }
+            }
+            if let Some(bar) = bar {
+                bar.finish_and_clear();
+                bars().remove(&bar);
+            }
+        });
+        Progress {
+            counters,
+            stop: Some(stop),
+            ticker: Some(ticker),
+        }
+    }
+
+    /// Wraps `input` so the bytes read from it count as compressed input.
+    pub fn reader<'a>(&'a self, input: &'a mut dyn Read) -> impl Read + 'a {
+        Counted {
+            inner: input,
+            bytes: &self.counters.bytes_in,
+        }
+    }
+
+    /// Records a finished file that compressed to `compressed` bytes.
+    pub fn done(&self, compressed: u64) {
+        self.counters.files.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_out.fetch_add(compressed, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(ticker) = self.ticker.take() {
+            let _ = ticker.join();
+        }
+    }
+}
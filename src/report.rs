@@ -0,0 +1,115 @@
+// The JSON report written by --report, for CI dashboards. Its layout is
+// described in the README; REPORT_VERSION goes up whenever a field changes
+// meaning or is removed, while new fields may be added without a bump.
+use crate::container;
+use crate::error::PackError;
+use crate::spill::SpilledEntry;
+use crate::{Job, Packed};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+pub const REPORT_VERSION: u32 = 1;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Prints a warning and keeps it for the report of the pack being prepared.
+pub fn warn(message: String) {
+    warn!("{}", message);
+    WARNINGS.lock().unwrap().push(message);
+}
+
+/// The warnings given since the last call.
+pub fn take_warnings() -> Vec<String> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap())
+}
+
+fn entry(job: &Job, kind: &str, entry: &SpilledEntry) -> Value {
+    if let Some(target) = entry.params.get("link").and_then(|link| container::link_target(link)) {
+        return json!({
+            "name": entry.name,
+            "kind": "symlink",
+            "target": target,
+        });
+    }
+    // Entries without a rule use the pack's level
+    let level = entry.params.get("level").and_then(|l| l.parse().ok()).unwrap_or(job.args.level);
+    let mut value = json!({
+        "name": entry.name,
+        "kind": kind,
+        "codec": entry.codec(),
+        "level": level,
+        "original": entry.original,
+        "compressed": entry.len,
+        "compress_secs": entry.secs,
+        "sha256": entry.sha256,
+    });
+    for key in ["filter", "rule", "mode"] {
+        if let Some(param) = entry.params.get(key) {
+            value[key] = json!(param);
+        }
+    }
+    if entry.params.contains_key("solid") {
+        value["solid"] = json!(true);
+    }
+    value
+}
+
+/// The report for `job`, packed into `packed` in `secs` seconds.
+pub fn build(job: &Job, packed: &Packed, secs: f64) -> Value {
+    let entries: Vec<Value> = std::iter::once(entry(job, "main", &packed.main))
+        .chain(packed.extras.iter().map(|e| entry(job, "extra", e)))
+        .collect();
+    let data = std::iter::once(&packed.main).chain(&packed.extras);
+    let (original, compressed) = data.fold((0, 0), |(original, compressed), e| (original + e.original, compressed + e.len));
+    let stub = &job.stub;
+    json!({
+        "report_version": REPORT_VERSION,
+        "execompress_version": env!("CARGO_PKG_VERSION"),
+        "input": job.input().display().to_string(),
+        "output": job.output().display().to_string(),
+        "input_size": packed.main.original,
+        "output_size": packed.size,
+        "duration_secs": secs,
+        "codec": job.codec.name(),
+        "level": job.args.level,
+        "stub": {
+            "size": packed.stub_len,
+            "gui": stub.gui,
+            "cleanup": stub.cleanup,
+            "manifest": stub.manifest,
+            "require_admin": stub.require_admin,
+        },
+        "version_info": {
+            "product_name": stub.product_name,
+            "company_name": stub.company_name,
+            "file_description": stub.file_description,
+            "product_version": stub.product_version,
+            "file_version": stub.file_version,
+            "copyright": stub.copyright,
+            "original_filename": stub.original_filename,
+        },
+        "metadata": job.metadata,
+        "entries": entries,
+        "totals": {
+            "entries": entries.len(),
+            "original": original,
+            "compressed": compressed,
+        },
+        "warnings": job.warnings,
+    })
+}
+
+/// Writes `report` to `path`, or to stdout for `-`.
+pub fn write(path: &Path, report: &Value) -> Result<(), PackError> {
+    let text = serde_json::to_string_pretty(report).unwrap();
+    if path == Path::new("-") {
+        println!("{}", text);
+    } else {
+        fs::write(path, text).map_err(|e| PackError::output(path, e))?;
+        info!("Report written to {:?}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use crate::codec::compress_stream;
+use crate::filter::Filter;
+use crate::spill::{Spill, SpilledEntry};
+use execompress_codecs::{self as codecs, Codec, Tuning};
+use globset::{Glob, GlobMatcher};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+
+/// What a rule compresses matching files with.
+#[derive(Clone, Copy)]
+pub enum Choice {
+    Fixed(&'static dyn Codec, u32),
+    /// Try every codec at the top level of its sweep and keep the smallest
+    Auto,
+}
+
+/// A `--rule '<glob>=<codec>[:<level>]'` or `--rule '<glob>=auto'` entry.
+pub struct Rule {
+    pub spec: String,
+    matcher: GlobMatcher,
+    pub choice: Choice,
+}
+
+impl Rule {
+    /// Parses a rule. Without an explicit level the rule uses `default_level`,
+    /// which must then be valid for the rule's codec.
+    pub fn parse(spec: &str, default_level: u32) -> Result<Rule, String> {
+        let (pattern, target) = spec
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Invalid rule '{}': expected <glob>=<codec>[:<level>] or <glob>=auto", spec))?;
+        let matcher = Glob::new(pattern)
+            .map_err(|e| format!("Invalid rule '{}': {}", spec, e))?
+            .compile_matcher();
+
+        let choice = if target == "auto" {
+            Choice::Auto
+        } else {
+            let (name, level) = match target.split_once(':') {
+                Some((name, level)) => {
+                    let level = level
+                        .parse::<u32>()
+                        .map_err(|_| format!("Invalid rule '{}': level '{}' is not a number", spec, level))?;
+                    (name, level)
+                }
+                None => (target, default_level),
+            };
+            let codec = codecs::by_name(name)
+                .ok_or_else(|| format!("Invalid rule '{}': unknown codec '{}'", spec, name))?;
+            if !codec.levels().contains(&level) {
+                return Err(format!(
+                    "Invalid rule '{}': {} supports levels {}-{}, got {}",
+                    spec,
+                    codec.name(),
+                    codec.levels().start(),
+                    codec.levels().end(),
+                    level
+                ));
+            }
+            Choice::Fixed(codec, level)
+        };
+
+        Ok(Rule {
+            spec: spec.to_string(),
+            matcher,
+            choice,
+        })
+    }
+}
+
+/// Returns the first rule matching the relative path of an extra file.
+pub fn find<'a>(rules: &'a [Rule], rel_path: &str) -> Option<&'a Rule> {
+    rules.iter().find(|r| r.matcher.is_match(rel_path))
+}
+
+/// Compresses the extra file read from `input` as `choice` says and records the
+/// codec and level in its entry header.
+pub fn compress(
+    spill: &Spill,
+    name: String,
+    input: &mut dyn Read,
+    choice: Choice,
+    tuning: &Tuning,
+    filter: Option<Filter>,
+) -> io::Result<SpilledEntry> {
+    let candidates: Vec<(&'static dyn Codec, u32)> = match choice {
+        Choice::Fixed(codec, level) => vec![(codec, level)],
+        Choice::Auto => codecs::registry()
+            .iter()
+            .map(|&c| (c, *c.sweep().last().unwrap()))
+            .collect(),
+    };
+
+    // Auto reads the file once per codec, so it is copied aside first
+    let staged = match choice {
+        Choice::Auto => Some(spill.stage(input)?),
+        Choice::Fixed(..) => None,
+    };
+    let mut best: Option<(SpilledEntry, u32)> = None;
+    let mut secs = 0.0;
+    for (codec, level) in candidates {
+        let compress = |input: &mut dyn Read, output: &mut dyn Write| {
+            compress_stream(codec, level, tuning, 1, filter, input, output)
+        };
+        let entry = match &staged {
+            Some(path) => spill.compress(name.clone(), &mut BufReader::new(File::open(path)?), compress)?,
+            None => spill.compress(name.clone(), input, compress)?,
+        };
+        secs += entry.secs;
+        if best.as_ref().is_none_or(|(b, _)| entry.len < b.len) {
+            best = Some((entry, level));
+        }
+    }
+
+    // Every candidate counts towards the time spent on the file
+    let (mut entry, level) = best.unwrap();
+    entry.secs = secs;
+    entry.params.insert("level".to_string(), level.to_string());
+    if let Choice::Auto = choice {
+        entry.params.insert("rule".to_string(), "auto".to_string());
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(spec: &str) -> String {
+        Rule::parse(spec, 3).err().expect(spec)
+    }
+
+    #[test]
+    fn parses_codec_and_level() {
+        let rule = Rule::parse("*.json=brotli:11", 3).unwrap();
+        assert_eq!(rule.spec, "*.json=brotli:11");
+        assert!(matches!(rule.choice, Choice::Fixed(codec, 11) if codec.name() == "brotli"));
+
+        // Without a level the default is used
+        let rule = Rule::parse("assets/**=zstd", 19).unwrap();
+        assert!(matches!(rule.choice, Choice::Fixed(codec, 19) if codec.name() == "zstd"));
+
+        assert!(matches!(Rule::parse("*.png=auto", 3).unwrap().choice, Choice::Auto));
+    }
+
+    #[test]
+    fn glob_may_contain_equals() {
+        let rule = Rule::parse("a=b/*.txt=lz4:1", 3).unwrap();
+        assert!(rule.matcher.is_match("a=b/x.txt"));
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        assert!(error("*.json").contains("expected <glob>=<codec>[:<level>] or <glob>=auto"));
+        assert!(error("*.json=nope").contains("unknown codec 'nope'"));
+        assert!(error("*.json=zstd:x").contains("level 'x' is not a number"));
+        assert!(error("*.json=lzma:12").contains("lzma supports levels 0-9, got 12"));
+        assert!(error("[=zstd").starts_with("Invalid rule '[=zstd'"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = vec![
+            Rule::parse("data/*.bin=lz4:1", 3).unwrap(),
+            Rule::parse("**/*.bin=zstd", 3).unwrap(),
+        ];
+        assert_eq!(find(&rules, "data/a.bin").map(|r| r.spec.as_str()), Some("data/*.bin=lz4:1"));
+        assert_eq!(find(&rules, "lib/b.bin").map(|r| r.spec.as_str()), Some("**/*.bin=zstd"));
+        assert!(find(&rules, "readme.txt").is_none());
+    }
+}
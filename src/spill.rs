@@ -0,0 +1,169 @@
+// Compressed entries are written to temporary files while packing, so memory
+// use stays at a few buffers per worker thread however large the inputs are.
+// The output is assembled from these files once every size is known.
+use execompress_codecs as codecs;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tempfile::{TempDir, TempPath};
+
+/// Directory holding the spill files of one pack; removed when dropped.
+pub struct Spill {
+    dir: TempDir,
+    next: AtomicUsize,
+}
+
+/// A compressed entry waiting in its spill file. The file is deleted when
+/// the entry is dropped.
+pub struct SpilledEntry {
+    pub name: String,
+    pub params: BTreeMap<String, String>,
+    /// Compressed size
+    pub len: u64,
+    /// Size and SHA-256 of the data read from the input
+    pub original: u64,
+    pub sha256: String,
+    /// Time spent compressing
+    pub secs: f64,
+    path: PathBuf,
+}
+
+/// Passes reads through, counting and hashing the bytes.
+struct Hashing<'a> {
+    inner: &'a mut dyn Read,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Read for Hashing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads the spill files of `entries` one after the other, opening each
+/// only when the previous one is used up.
+struct Concat<'a> {
+    entries: std::slice::Iter<'a, SpilledEntry>,
+    current: Option<BufReader<File>>,
+}
+
+impl Read for Concat<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let n = current.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.entries.next() {
+                Some(entry) => self.current = Some(entry.open()?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+impl Spill {
+    pub fn new() -> io::Result<Spill> {
+        Ok(Spill {
+            dir: tempfile::Builder::new().prefix("execompress-").tempdir()?,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Runs `compress` (usually `codec::compress_stream`) from `input` into
+    /// a new spill file. It returns the entry header params.
+    pub fn compress<F>(&self, name: String, input: &mut dyn Read, compress: F) -> io::Result<SpilledEntry>
+    where
+        F: FnOnce(&mut dyn Read, &mut dyn Write) -> io::Result<BTreeMap<String, String>>,
+    {
+        let path = self.dir.path().join(self.next.fetch_add(1, Ordering::Relaxed).to_string());
+        let mut output = BufWriter::new(File::create(&path)?);
+        let start = Instant::now();
+        let mut input = Hashing {
+            inner: input,
+            hasher: Sha256::new(),
+            len: 0,
+        };
+        let params = compress(&mut input, &mut output)?;
+        output.flush()?;
+        drop(output);
+
+        Ok(SpilledEntry {
+            name,
+            params,
+            len: fs::metadata(&path)?.len(),
+            original: input.len,
+            sha256: format!("{:x}", input.hasher.finalize()),
+            secs: start.elapsed().as_secs_f64(),
+            path,
+        })
+    }
+
+    /// Runs `compress` over the spill files of `members`, staged uncompressed
+    /// by `Spill::compress`, as one stream: a solid block. The first member
+    /// gets the compressed block and the others are left empty; all of them
+    /// get the params `compress` returns.
+    pub fn solid<F>(&self, members: &mut [SpilledEntry], compress: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut dyn Read, &mut dyn Write) -> io::Result<BTreeMap<String, String>>,
+    {
+        let mut input = Concat {
+            entries: members.iter(),
+            current: None,
+        };
+        let mut block = self.compress(String::new(), &mut input, compress)?;
+        for (i, member) in members.iter_mut().enumerate() {
+            member.params.extend(block.params.clone());
+            if i == 0 {
+                // The member's staged file goes when the block entry is dropped
+                std::mem::swap(&mut member.path, &mut block.path);
+                member.len = block.len;
+                member.secs = block.secs;
+            } else {
+                File::create(&member.path)?;
+                member.len = 0;
+                member.secs = 0.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `input` uncompressed into the spill directory, for data that
+    /// has to be read more than once but can only be streamed.
+    pub fn stage(&self, input: &mut dyn Read) -> io::Result<TempPath> {
+        let mut file = tempfile::Builder::new().tempfile_in(self.dir.path())?;
+        io::copy(input, &mut BufWriter::new(file.as_file_mut()))?;
+        Ok(file.into_temp_path())
+    }
+}
+
+impl SpilledEntry {
+    pub fn codec(&self) -> &'static str {
+        self.params
+            .get("codec")
+            .and_then(|id| id.parse().ok())
+            .and_then(codecs::by_id)
+            .map(|c| c.name())
+            .unwrap_or("unknown")
+    }
+
+    pub fn open(&self) -> io::Result<BufReader<File>> {
+        Ok(BufReader::new(File::open(&self.path)?))
+    }
+}
+
+impl Drop for SpilledEntry {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
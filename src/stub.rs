@@ -1,39 +1,163 @@
 use std::process::Command;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::io;
+use crate::error::PackError;
+use crate::progress::Progress;
+use log::debug;
 
-pub fn get_stub_exe(gui: bool) -> Vec<u8> {
+/// Everything stub_loader/build.rs reads, written as files next to it.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct StubSettings {
+    pub gui: bool,
+    pub cleanup: bool,
+    /// Largest icon of the input, as an .ico file
+    pub icon: Option<Vec<u8>>,
+    pub product_name: Option<String>,
+    pub company_name: Option<String>,
+    pub file_description: Option<String>,
+    pub product_version: Option<String>,
+    pub file_version: Option<String>,
+    pub copyright: Option<String>,
+    pub original_filename: String,
+    pub require_admin: bool,
+    pub manifest: bool,
+}
+
+impl StubSettings {
+    /// The settings that change the built stub, so packs with equal keys can
+    /// share one build. Icon, version info and manifest are Windows
+    /// resources; elsewhere only the GUI feature matters.
+    pub fn key(&self) -> StubSettings {
+        if cfg!(windows) {
+            StubSettings {
+                cleanup: false,
+                ..self.clone()
+            }
+        } else {
+            StubSettings {
+                gui: self.gui,
+                ..StubSettings::default()
+            }
+        }
+    }
+
+    /// Writes the files for build.rs, removing those of unset options.
+    pub fn write(&self) -> Result<(), PackError> {
+        let stub_dir = PathBuf::from("stub_loader");
+        let flag = |set: bool| set.then(|| b"true".to_vec());
+        let text = |value: &Option<String>| value.as_ref().map(|v| v.as_bytes().to_vec());
+        for (name, content) in [
+            ("icon.ico", self.icon.clone()),
+            ("gui.txt", flag(self.gui)),
+            ("cleanup.txt", flag(self.cleanup)),
+            ("product_name.txt", text(&self.product_name)),
+            ("company_name.txt", text(&self.company_name)),
+            ("file_description.txt", text(&self.file_description)),
+            ("product_version.txt", text(&self.product_version)),
+            ("file_version.txt", text(&self.file_version)),
+            ("copyright.txt", text(&self.copyright)),
+            ("original_filename.txt", Some(self.original_filename.as_bytes().to_vec())),
+            ("require_admin.txt", flag(self.require_admin)),
+            ("manifest.txt", flag(self.manifest)),
+        ] {
+            let path = stub_dir.join(name);
+            let _ = fs::remove_file(&path);
+            if let Some(content) = content {
+                fs::write(&path, content).map_err(|e| PackError::resource(&path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the settings and builds the stub with them.
+    pub fn build(&self) -> Result<Vec<u8>, PackError> {
+        self.write()?;
+        get_stub_exe(self.gui, None).map_err(|e| PackError::stub(Path::new("stub_loader"), e))
+    }
+
+    /// Builds the stub a second time in a fresh target directory, so
+    /// --check-reproducible also compares two independent stub builds.
+    pub fn rebuild(&self) -> Result<Vec<u8>, PackError> {
+        let stub_dir = Path::new("stub_loader");
+        let target_dir = tempfile::tempdir().map_err(|e| PackError::stub(stub_dir, e))?;
+        self.write()?;
+        get_stub_exe(self.gui, Some(target_dir.path())).map_err(|e| PackError::stub(stub_dir, e))
+    }
+}
+
+/// Builds stub_loader and returns the executable. Without a `target_dir`
+/// it builds in stub_loader/target, cleaned first.
+pub fn get_stub_exe(gui: bool, target_dir: Option<&Path>) -> io::Result<Vec<u8>> {
     let stub_dir = PathBuf::from("stub_loader");
+    let _progress = Progress::task("Building stub");
 
     // Clean previous build to force rebuild with new version info
     // This ensures version info changes are always picked up
-    let _ = Command::new("cargo")
-        .args(&["clean"])
-        .current_dir(&stub_dir)
-        .output();
+    if target_dir.is_none() {
+        let _ = Command::new("cargo")
+            .args(["clean"])
+            .current_dir(&stub_dir)
+            .output();
+    }
 
     // Set build arguments
     let mut args = vec!["build", "--release"];
+    let target_arg;
+    if let Some(dir) = target_dir {
+        target_arg = dir.to_string_lossy().to_string();
+        args.push("--target-dir");
+        args.push(&target_arg);
+    }
+    let mut features = vec![];
     if gui {
+        features.push("gui");
+    }
+    // The stub must be able to decode every codec the packer can write
+    if cfg!(feature = "bzip2") {
+        features.push("bzip2");
+    }
+    if cfg!(feature = "deflate") {
+        features.push("deflate");
+    }
+    let features = features.join(",");
+    if !features.is_empty() {
         args.push("--features");
-        args.push("gui");
+        args.push(&features);
     }
 
     // Run cargo build
-    let output = Command::new("cargo")
-        .args(&args)
-        .current_dir(&stub_dir)
+    let mut command = Command::new("cargo");
+    command.args(&args).current_dir(&stub_dir);
+    // link.exe stamps the build time into the image unless told not to;
+    // /Brepro makes the stub, and so the packed output, reproducible
+    if cfg!(target_env = "msvc") {
+        let flags = std::env::var("RUSTFLAGS").unwrap_or_default();
+        command.env("RUSTFLAGS", format!("{} -C link-arg=/Brepro", flags).trim());
+    }
+    let output = command
         .output()
-        .expect("Failed to build stub");
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run cargo: {}", e)))?;
 
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("cargo {} in {}:\n{}", args.join(" "), stub_dir.display(), stderr.trim_end());
     if !output.status.success() {
-        panic!(
-            "Stub build failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+        // cargo's error lines, without the compile progress around them
+        let errors: Vec<&str> = stderr.lines().filter(|line| line.starts_with("error")).collect();
+        let summary = if errors.is_empty() {
+            stderr.lines().last().unwrap_or_default().to_string()
+        } else {
+            errors.join("; ")
+        };
+        return Err(io::Error::other(format!(
+            "cargo failed ({}): {}; run with -v or --log-file for its full output",
+            output.status, summary
+        )));
     }
 
     // Read the result
-    fs::read(stub_dir.join("target/release/stub_loader.exe"))
-        .expect("Failed to read built stub_loader.exe")
+    let stub_name = format!("stub_loader{}", std::env::consts::EXE_SUFFIX);
+    let target_dir = target_dir.map_or_else(|| stub_dir.join("target"), Path::to_path_buf);
+    fs::read(target_dir.join("release").join(&stub_name))
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot read the built {}: {}", stub_name, e)))
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
 use std::path::Path;
+use crate::pe::{PeFile, RT_VERSION};
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct VersionInfo {
     pub product_name: Option<String>,
     pub company_name: Option<String>,
@@ -10,10 +12,89 @@ pub struct VersionInfo {
     pub copyright: Option<String>,
 }
 
-// Simplified version: returns None for now
-// TODO: Implement proper PE version info extraction using pelite or windows-rs
-pub fn extract_version_info<P: AsRef<Path>>(_path: P) -> Option<VersionInfo> {
-    // For now, return None so it falls back to defaults
-    // This can be enhanced later with proper PE parsing
-    None
+pub fn extract_version_info<P: AsRef<Path>>(path: P) -> Option<VersionInfo> {
+    let file = std::fs::File::open(path).ok()?;
+    // Mapped rather than read, as only the headers and the resource are needed
+    // SAFETY: the file is only read and is not expected to change meanwhile
+    let data = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+    extract_version_info_from_bytes(&data)
+}
+
+/// Reads the StringFileInfo block of the RT_VERSION resource.
+pub fn extract_version_info_from_bytes(data: &[u8]) -> Option<VersionInfo> {
+    let pe = PeFile::parse(data)?;
+    let resource = pe.find_resource(RT_VERSION)?;
+
+    let mut strings = HashMap::new();
+    collect_strings(resource, 0, resource.len(), 0, &mut strings);
+    if strings.is_empty() {
+        return None;
+    }
+
+    Some(VersionInfo {
+        product_name: strings.remove("ProductName"),
+        company_name: strings.remove("CompanyName"),
+        file_description: strings.remove("FileDescription"),
+        product_version: strings.remove("ProductVersion"),
+        file_version: strings.remove("FileVersion"),
+        copyright: strings.remove("LegalCopyright"),
+    })
+}
+
+fn align4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+}
+
+/// Reads a NUL-terminated UTF-16 string, returning it and the position after the terminator.
+fn read_utf16(data: &[u8], mut pos: usize, end: usize) -> Option<(String, usize)> {
+    let mut units = vec![];
+    while pos + 2 <= end {
+        let unit = read_u16(data, pos)?;
+        pos += 2;
+        if unit == 0 {
+            break;
+        }
+        units.push(unit);
+    }
+    Some((String::from_utf16_lossy(&units), pos))
+}
+
+/// Walks the nested VS_VERSIONINFO blocks. Depth 3 blocks (below VS_VERSIONINFO,
+/// StringFileInfo and a StringTable) are the key/value strings.
+fn collect_strings(data: &[u8], pos: usize, end: usize, depth: usize, out: &mut HashMap<String, String>) {
+    let mut pos = pos;
+    while pos + 6 <= end {
+        let Some(length) = read_u16(data, pos).map(|l| l as usize) else { return };
+        if length == 0 || pos + length > end {
+            return;
+        }
+        let block_end = pos + length;
+        let value_length = read_u16(data, pos + 2).unwrap_or(0) as usize;
+        let value_type = read_u16(data, pos + 4).unwrap_or(0);
+        let Some((key, after_key)) = read_utf16(data, pos + 6, block_end) else { return };
+        let value_start = align4(after_key);
+
+        if depth == 3 {
+            if value_length > 0 {
+                let value_end = if value_type == 1 { value_start + value_length * 2 } else { value_start + value_length };
+                if let Some((value, _)) = read_utf16(data, value_start, value_end.min(block_end)) {
+                    out.insert(key, value.trim().to_string());
+                }
+            }
+        } else if depth != 1 || key == "StringFileInfo" {
+            // Binary value (VS_FIXEDFILEINFO at the root) precedes the children
+            let value_bytes = if value_type == 1 { value_length * 2 } else { value_length };
+            let children = align4(value_start + value_bytes);
+            collect_strings(data, children, block_end, depth + 1, out);
+        }
+
+        if depth == 0 {
+            return;
+        }
+        pos = align4(block_end);
+    }
 }
@@ -1,5 +1,6 @@
 use std::path::Path;
 use std::fs;
+use std::env;
 
 fn read_file_if_exists(path: &str) -> Option<String> {
     if Path::new(path).exists() {
@@ -84,10 +85,15 @@ fn generate_manifest(
 fn main() {
     println!("cargo:warning=build.rs is running!");
 
-    if Path::new("gui.txt").exists() {
-        println!("cargo:rustc-link-arg=/SUBSYSTEM:WINDOWS");
-    } else {
-        println!("cargo:rustc-link-arg=/SUBSYSTEM:CONSOLE");
+    // Subsystem flags and version resources only exist for Windows targets
+    let target_windows = env::var("CARGO_CFG_TARGET_OS").map(|os| os == "windows").unwrap_or(false);
+
+    if target_windows {
+        if Path::new("gui.txt").exists() {
+            println!("cargo:rustc-link-arg=/SUBSYSTEM:WINDOWS");
+        } else {
+            println!("cargo:rustc-link-arg=/SUBSYSTEM:CONSOLE");
+        }
     }
 
     println!("cargo:rerun-if-changed=configs/gui.txt");
@@ -102,6 +108,10 @@ fn main() {
     println!("cargo:rerun-if-changed=require_admin.txt");
     println!("cargo:rerun-if-changed=manifest.txt");
 
+    if !target_windows {
+        return;
+    }
+
     let mut res = winres::WindowsResource::new();
 
     if Path::new("icon.ico").exists() {
@@ -0,0 +1,263 @@
+// Reverses the branch filters execompress applies before compression.
+// Must stay in sync with src/filter.rs in the packer.
+use std::io::{self, Write};
+
+// Decompressed bytes collected before each filter pass
+const STREAM_CHUNK: usize = 1024 * 1024;
+
+#[derive(Clone, Copy)]
+enum Filter {
+    X86,
+    Arm64,
+}
+
+/// Undoes the filter named in an entry header on everything written through
+/// it, `STREAM_CHUNK` bytes at a time. Filters with an "xz-" prefix are part
+/// of the xz chain and were already reversed by the decoder, so like unknown
+/// names they pass the data through unchanged. `finish` must be called to
+/// write the last bytes.
+pub struct Decoder<W: Write> {
+    inner: W,
+    filter: Option<Filter>,
+    state: X86State,
+    buffer: Vec<u8>,
+    /// Stream offset of `buffer[0]`
+    offset: u32,
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(name: Option<&str>, inner: W) -> Self {
+        let filter = match name {
+            Some("x86") => Some(Filter::X86),
+            Some("arm64") => Some(Filter::Arm64),
+            _ => None,
+        };
+        Decoder {
+            inner,
+            filter,
+            state: X86State::default(),
+            buffer: vec![],
+            offset: 0,
+        }
+    }
+
+    /// Reverses the filter on the buffered bytes and writes out those that are
+    /// final. At the end of the stream that is all of them.
+    fn drain(&mut self, end: bool) -> io::Result<()> {
+        let done = match self.filter {
+            Some(Filter::X86) => x86_code(&mut self.buffer, self.offset, &mut self.state, false),
+            Some(Filter::Arm64) => arm64_code(&mut self.buffer, self.offset, false),
+            None => self.buffer.len(),
+        };
+        let done = if end { self.buffer.len() } else { done };
+        self.inner.write_all(&self.buffer[..done])?;
+        self.buffer.drain(..done);
+        self.offset = self.offset.wrapping_add(done as u32);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.drain(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.filter.is_none() {
+            return self.inner.write(data);
+        }
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= STREAM_CHUNK {
+            self.drain(false)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn test_ms_byte(b: u8) -> bool {
+    b == 0 || b == 0xFF
+}
+
+/// x86 filter state carried between buffers of one stream.
+#[derive(Clone, Copy)]
+struct X86State {
+    prev_mask: u32,
+    /// Stream offset of the last E8/E9 byte seen
+    prev_pos: u32,
+}
+
+impl Default for X86State {
+    fn default() -> Self {
+        X86State {
+            prev_mask: 0,
+            prev_pos: 0u32.wrapping_sub(5),
+        }
+    }
+}
+
+/// E8/E9 (CALL/JMP rel32) conversion, same state machine as xz's x86 filter.
+/// `buffer` starts `offset` bytes into the stream; returns how many leading
+/// bytes are final.
+fn x86_code(buffer: &mut [u8], offset: u32, state: &mut X86State, is_encoder: bool) -> usize {
+    const MASK_TO_ALLOWED_STATUS: [bool; 8] = [true, true, true, false, true, false, false, false];
+    const MASK_TO_BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+
+    if buffer.len() < 5 {
+        return 0;
+    }
+    let X86State { mut prev_mask, mut prev_pos } = *state;
+    let limit = buffer.len() - 5;
+    let mut pos = 0usize;
+
+    while pos <= limit {
+        let b = buffer[pos];
+        if b != 0xE8 && b != 0xE9 {
+            pos += 1;
+            continue;
+        }
+
+        let stream_pos = offset.wrapping_add(pos as u32);
+        let offset = stream_pos.wrapping_sub(prev_pos);
+        prev_pos = stream_pos;
+        if offset > 5 {
+            prev_mask = 0;
+        } else {
+            for _ in 0..offset {
+                prev_mask &= 0x77;
+                prev_mask <<= 1;
+            }
+        }
+
+        let b = buffer[pos + 4];
+        if test_ms_byte(b) && MASK_TO_ALLOWED_STATUS[((prev_mask >> 1) & 0x7) as usize] && (prev_mask >> 1) < 0x10 {
+            let mut src = u32::from_le_bytes([buffer[pos + 1], buffer[pos + 2], buffer[pos + 3], b]);
+            let mut dest;
+            loop {
+                let here = stream_pos.wrapping_add(5);
+                dest = if is_encoder { src.wrapping_add(here) } else { src.wrapping_sub(here) };
+                if prev_mask == 0 {
+                    break;
+                }
+                let i = MASK_TO_BIT_NUMBER[(prev_mask >> 1) as usize];
+                let b = (dest >> (24 - i * 8)) as u8;
+                if !test_ms_byte(b) {
+                    break;
+                }
+                src = dest ^ ((1u32 << (32 - i * 8)) - 1);
+            }
+            buffer[pos + 4] = !(((dest >> 24) & 1).wrapping_sub(1)) as u8;
+            buffer[pos + 3] = (dest >> 16) as u8;
+            buffer[pos + 2] = (dest >> 8) as u8;
+            buffer[pos + 1] = dest as u8;
+            pos += 5;
+            prev_mask = 0;
+        } else {
+            pos += 1;
+            prev_mask |= 1;
+            if test_ms_byte(b) {
+                prev_mask |= 0x10;
+            }
+        }
+    }
+
+    *state = X86State { prev_mask, prev_pos };
+    pos
+}
+
+/// BL and ADRP conversion, same as xz's ARM64 filter.
+fn arm64_code(buffer: &mut [u8], offset: u32, is_encoder: bool) -> usize {
+    let mut i = 0usize;
+    while i + 4 <= buffer.len() {
+        let pc = offset.wrapping_add(i as u32);
+        let mut instr = u32::from_le_bytes([buffer[i], buffer[i + 1], buffer[i + 2], buffer[i + 3]]);
+
+        if (instr >> 26) == 0x25 {
+            // BL
+            let src = instr;
+            let pc = if is_encoder { pc >> 2 } else { 0u32.wrapping_sub(pc >> 2) };
+            instr = 0x94000000 | (src.wrapping_add(pc) & 0x03FFFFFF);
+            buffer[i..i + 4].copy_from_slice(&instr.to_le_bytes());
+        } else if (instr & 0x9F000000) == 0x90000000 {
+            // ADRP
+            let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001FFFFC);
+            if (src.wrapping_add(0x00020000) & 0x001C0000) == 0 {
+                let pc = if is_encoder { pc >> 12 } else { 0u32.wrapping_sub(pc >> 12) };
+                let dest = src.wrapping_add(pc);
+                instr &= 0x9000001F;
+                instr |= (dest & 3) << 29;
+                instr |= (dest & 0x0003FFFC) << 3;
+                instr |= 0u32.wrapping_sub(dest & 0x00020000) & 0x00E00000;
+                buffer[i..i + 4].copy_from_slice(&instr.to_le_bytes());
+            }
+        }
+        i += 4;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random bytes with x86 CALLs and ARM64 BL/ADRP instructions
+    /// mixed in, long enough to span several stream chunks.
+    fn code_like(len: usize) -> Vec<u8> {
+        let mut seed = 0x2545_f491u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            seed >> 8
+        };
+        let mut data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        for i in (0..len - 16).step_by(64) {
+            let rel = next() & 0xFFFF;
+            data[i..i + 5].copy_from_slice(&[0xE8, rel as u8, (rel >> 8) as u8, 0, 0]);
+            let bl = 0x9400_0000 | (next() & 0x03FF_FFFF);
+            data[i + 8..i + 12].copy_from_slice(&bl.to_le_bytes());
+            let adrp = 0x9000_0000 | ((next() & 0x3) << 29) | ((next() & 0x7FF) << 5);
+            data[i + 12..i + 16].copy_from_slice(&adrp.to_le_bytes());
+        }
+        data
+    }
+
+    /// Encodes `data` in one pass, as the packer does for a whole file.
+    fn encode(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut encoded = data.to_vec();
+        match name {
+            "x86" => x86_code(&mut encoded, 0, &mut X86State::default(), true),
+            _ => arm64_code(&mut encoded, 0, true),
+        };
+        encoded
+    }
+
+    #[test]
+    fn round_trip_in_pieces() {
+        let data = code_like(STREAM_CHUNK * 2 + 12_345);
+        for name in ["x86", "arm64"] {
+            let encoded = encode(name, &data);
+            assert_ne!(encoded, data, "{} changed nothing", name);
+            for piece in [1, 4_099, STREAM_CHUNK + 1] {
+                let mut decoder = Decoder::new(Some(name), vec![]);
+                for chunk in encoded.chunks(piece) {
+                    decoder.write_all(chunk).unwrap();
+                }
+                assert_eq!(decoder.finish().unwrap(), data, "{} in pieces of {}", name, piece);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_and_xz_filters_pass_through() {
+        let data = code_like(4_096);
+        for name in [None, Some("xz-x86"), Some("unknown")] {
+            let mut decoder = Decoder::new(name, vec![]);
+            decoder.write_all(&data).unwrap();
+            assert_eq!(decoder.finish().unwrap(), data);
+        }
+    }
+}
@@ -1,14 +1,11 @@
 #![cfg_attr(feature = "gui", windows_subsystem = "windows")]
-use base64::{engine::general_purpose::STANDARD, Engine as _};
+use memmap2::Mmap;
 use std::fs::File;
 use std::fs::remove_dir_all;
-use std::io::{Write, Read};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::process::Command;
 use std::env;
-use xz2::read::XzDecoder;
-use brotli::Decompressor;
-use close_file::Closable;
 use rand::{distributions::Alphanumeric, Rng};
 use fs_more::file::remove_file;
 use std::io;
@@ -16,6 +13,9 @@ use std::fs;
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
 
+mod filter;
+mod payload;
+
 pub fn delete_all_files_in_folder(dir_path: &Path) -> io::Result<()> {
     for entry in fs::read_dir(dir_path.clone())? {
         let entry = entry?;
@@ -41,117 +41,203 @@ fn generate_random_string(len: usize) -> String {
         .collect()
 }
 
-fn main() {
-    let exe_path = std::env::current_exe().unwrap();
-    let mut file = File::open(&exe_path).unwrap();
-    let mut buffer = vec![];
-    file.read_to_end(&mut buffer).unwrap();
-
-    let marker_file_content = b"--FILE-CONTENT--\n";
-    let marker_filename = b"--XFILENAMEX--\n";
-	let marker_extra = b"--EXTRA-FILE--\n";
-	let marker_cleanup = b"--CLEANUP--\n";
-
-    let mut file_content_start = None;
-    let mut filename_start = None;
-	let mut extra_starts = vec![];
-	let mut cleanup_enabled = false;
-
-    for i in 0..buffer.len() {
-        if i + marker_file_content.len() <= buffer.len() && &buffer[i..i + marker_file_content.len()] == marker_file_content {
-            file_content_start = Some(i + marker_file_content.len());
-        }
-        if i + marker_filename.len() <= buffer.len() && &buffer[i..i + marker_filename.len()] == marker_filename {
-            filename_start = Some(i + marker_filename.len());
-        }
-		if i + marker_extra.len() <= buffer.len() && &buffer[i..i + marker_extra.len()] == marker_extra {
-            extra_starts.push(i + marker_extra.len());
-        }
-		if i + marker_cleanup.len() <= buffer.len() && &buffer[i..i + marker_cleanup.len()] == marker_cleanup {
-            cleanup_enabled = true;
-        }
+/// Decompresses `entry` from the packed executable at `exe_path` into `dest`,
+/// reading and writing in small pieces.
+fn extract(exe_path: &Path, entry: &payload::Entry, dest: &Path) -> io::Result<()> {
+    let output = BufWriter::new(File::create(dest)?);
+    let mut output = filter::Decoder::new(entry.header.filter.as_deref(), output);
+    decode(exe_path, entry, &mut output)?;
+    output.finish()?;
+    Ok(())
+}
+
+/// Decompresses the solid block stored in `files[0]` into the files under
+/// `dir`, each through its own filter.
+fn extract_solid(exe_path: &Path, files: &[payload::Entry], dir: &Path) -> io::Result<()> {
+    let mut output = Split {
+        dir,
+        files: files.iter(),
+        current: None,
+    };
+    decode(exe_path, &files[0], &mut output)?;
+    output.advance()?;
+    match output.current {
+        Some((entry, _, _)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Solid block ends before the end of {}", entry.name),
+        )),
+        None => Ok(()),
     }
+}
 
-    let mut xfilenamex = "output.exe".to_string();
+/// Writes a solid block's stream across its files in turn.
+struct Split<'a> {
+    dir: &'a Path,
+    files: std::slice::Iter<'a, payload::Entry>,
+    /// The file being written and the bytes still due to it
+    current: Option<(&'a payload::Entry, filter::Decoder<BufWriter<File>>, u64)>,
+}
 
-    if let Some(fstart) = filename_start {
-        if let Some(ploc) = buffer[fstart..].iter().position(|&c| c == b'\n') {
-            let filename_encoded = &buffer[fstart..fstart + ploc];
-            if let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(filename_encoded) {
-                xfilenamex = String::from_utf8_lossy(&decoded).to_string();
+impl Split<'_> {
+    /// Closes the current file once it is complete and opens the next one,
+    /// creating any empty files on the way. `current` is left empty after
+    /// the last file.
+    fn advance(&mut self) -> io::Result<()> {
+        while self.current.as_ref().is_none_or(|(_, _, left)| *left == 0) {
+            if let Some((entry, output, _)) = self.current.take() {
+                output.finish()?;
+                set_mode(&self.dir.join(&entry.name), entry);
             }
+            let Some(entry) = self.files.next() else {
+                return Ok(());
+            };
+            let dest = self.dir.join(&entry.name);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).ok();
+            }
+            let output = filter::Decoder::new(entry.header.filter.as_deref(), BufWriter::new(File::create(&dest)?));
+            self.current = Some((entry, output, entry.header.solid.unwrap_or(0)));
         }
+        Ok(())
     }
+}
 
-    let compressed_data = if let Some(fc_start) = file_content_start {
-        if let Some(newline) = buffer[fc_start..].iter().position(|&b| b == b'\n') {
-            let len_str = String::from_utf8_lossy(&buffer[fc_start..fc_start + newline]);
-            if let Ok(content_len) = len_str.trim().parse::<usize>() {
-                let bin_start = fc_start + newline + 1;
-                buffer[bin_start..bin_start + content_len].to_vec()
-            } else {
-                panic!("Invalid file content length");
-            }
-        } else {
-            panic!("Could not find file content length marker");
+impl Write for Split<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.advance()?;
+        let Some((_, output, left)) = &mut self.current else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Solid block holds more data than its files"));
+        };
+        let n = output.write(&data[..data.len().min(usize::try_from(*left).unwrap_or(usize::MAX))])?;
+        *left -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((_, output, _)) => output.flush(),
+            None => Ok(()),
         }
+    }
+}
+
+/// Sets the permission bits recorded for a file packed from an archive.
+fn set_mode(path: &Path, entry: &payload::Entry) {
+    #[cfg(unix)]
+    if let Some(mode) = entry.header.mode {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(mode));
+    }
+    #[cfg(not(unix))]
+    let _ = (path, entry);
+}
+
+/// Decompresses the data of `entry` into `output`.
+fn decode(exe_path: &Path, entry: &payload::Entry, output: &mut dyn Write) -> io::Result<()> {
+    let mut source = File::open(exe_path)?;
+    source.seek(SeekFrom::Start(entry.offset))?;
+    let mut input = BufReader::new(source.take(entry.header.len));
+
+    let codec = match entry.header.codec {
+        Some(id) => execompress_codecs::by_id(id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, format!("Codec {} is not enabled in this stub", id))
+        })?,
+        None => execompress_codecs::detect(input.fill_buf()?),
+    };
+
+    codec
+        .decode(&mut input, output)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to decompress {}: {}", codec.name(), e)))
+}
+
+/// Recreates a symlink packed from an archive. Windows only allows this
+/// with developer mode or admin rights, so a failure is reported and skipped.
+fn symlink(target: &str, dest: &Path) {
+    #[cfg(unix)]
+    let result = std::os::unix::fs::symlink(target, dest);
+    #[cfg(windows)]
+    let result = if dest.parent().map(|dir| dir.join(target).is_dir()).unwrap_or(false) {
+        std::os::windows::fs::symlink_dir(target, dest)
     } else {
-        panic!("File content marker not found");
+        std::os::windows::fs::symlink_file(target, dest)
     };
+    if let Err(e) = result {
+        eprintln!("Failed to create symlink {} -> {}: {}", dest.display(), target, e);
+    }
+}
 
-	let decompressed = decompress(&compressed_data);
-    let px = Path::new(&xfilenamex)
+fn main() {
+    let exe_path = std::env::current_exe().unwrap();
+    let payload = {
+        let file = File::open(&exe_path).unwrap();
+        // Only the stub itself and the entry headers are paged in; entry
+        // data is read from the file as it is decompressed
+        // SAFETY: the executable is only read, and is not modified while running
+        let image = unsafe { Mmap::map(&file) }.unwrap();
+        payload::locate(&image).expect("Payload not found")
+    };
+
+    let px = Path::new(&payload.main.name)
         .file_name()       // Gets just the filename (OsStr)
         .unwrap()          // or handle Option
         .to_string_lossy() // Converts OsStr to String
         .to_string();
     let mut path = env::temp_dir();
     path.push(generate_random_string(10));
-	let mut path_dir = path.clone();
+	let path_dir = path.clone();
     std::fs::create_dir_all(&path);
     path.push(px.to_string());
 
-    let mut file = File::create(&path).expect("Failed to create temp exe");
-    file.write_all(&decompressed).expect("Failed to write payload");
+    extract(&exe_path, &payload.main, &path)
+        .unwrap_or_else(|e| panic!("Failed to write {}: {}", payload.main.name, e));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(&path, fs::Permissions::from_mode(0o755));
+    }
 
     let path2 = path.display().to_string();
     let original_exe_dir = env::current_exe()
         .ok()
         .and_then(|p| p.parent().map(|p| p.to_path_buf()))
         .unwrap_or_else(|| ".".into());
-    file.close();
 	
-	//for start in extra_starts {
 	let pool = ThreadPoolBuilder::new()
 				.num_threads(4)
 				.build()
 				.unwrap();
 
 	pool.install(|| {
-		extra_starts.into_iter().par_bridge().for_each(|start| {
-			if let Some(path_len_pos) = buffer[start..].iter().position(|&b| b == b'\n') {
-				let path_str = String::from_utf8_lossy(&buffer[start..start + path_len_pos]).to_string();
-				let mut full_path = path_dir.clone();
-				if let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(path_str) {
-					full_path = path_dir.join(String::from_utf8_lossy(&decoded).to_string());
-				}
-
-				let content_start = start + path_len_pos + 1;
-				if let Some(len_end) = buffer[content_start..].iter().position(|&b| b == b'\n') {
-					let len_str = String::from_utf8_lossy(&buffer[content_start..content_start + len_end]);
-					if let Ok(payload_len) = len_str.trim().parse::<usize>() {
-						let bin_start = content_start + len_end + 1;
-						let compressed = &buffer[bin_start..bin_start + payload_len];
-						let content = decompress(compressed);
-						if let Some(parent) = full_path.parent() {
-							fs::create_dir_all(parent).ok();
-						}
-						File::create(&full_path).unwrap().write_all(&content).unwrap();
-					}
-				}
+		payload::jobs(&payload.extras).par_iter().for_each(|&files| {
+			let entry = &files[0];
+			if entry.header.link.is_some() {
+				return;
 			}
+			if entry.header.solid.is_some() {
+				extract_solid(&exe_path, files, &path_dir)
+					.unwrap_or_else(|e| panic!("Failed to write the solid block from {}: {}", entry.name, e));
+				return;
+			}
+			let full_path = path_dir.join(&entry.name);
+			if let Some(parent) = full_path.parent() {
+				fs::create_dir_all(parent).ok();
+			}
+			extract(&exe_path, entry, &full_path)
+				.unwrap_or_else(|e| panic!("Failed to write {}: {}", entry.name, e));
+			set_mode(&full_path, entry);
 		});
     });
+
+	// Symlinks last, so that on Windows their targets exist to tell files from directories
+	for entry in &payload.extras {
+		if let Some(target) = &entry.header.link {
+			let full_path = path_dir.join(&entry.name);
+			if let Some(parent) = full_path.parent() {
+				fs::create_dir_all(parent).ok();
+			}
+			symlink(target, &full_path);
+		}
+	}
 	
     let mut child = Command::new(&path2)
         .current_dir(original_exe_dir)
@@ -161,40 +247,8 @@ fn main() {
 	let _ = child.wait();
 
 	// Only cleanup if --cleanup flag was specified during compression
-	if cleanup_enabled {
+	if payload.cleanup {
 		delete_all_files_in_folder(&path_dir);
 		remove_dir_all(&path_dir);
 	}
 }
-
-fn decompress(data: &[u8]) -> Vec<u8> {
-    let decompressed = if let Ok(decompressed) = decompress_brotli(data) {
-        decompressed
-    } else if let Ok(decompressed) = decompress_lzma(data) {
-        decompressed
-    } else {
-        decompress_zstd(data).expect("Failed to decompress zstd")
-    };
-	return decompressed;
-}
-
-fn decompress_lzma(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut decoder = XzDecoder::new(data);
-    let mut out = vec![];
-    std::io::copy(&mut decoder, &mut out)?;
-    Ok(out)
-}
-
-fn decompress_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut decoder = zstd::stream::Decoder::new(data)?;
-    let mut out = vec![];
-    std::io::copy(&mut decoder, &mut out)?;
-    Ok(out)
-}
-
-fn decompress_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut decoder = Decompressor::new(data, 4096);
-    let mut out = vec![];
-    std::io::copy(&mut decoder, &mut out)?;
-    Ok(out)
-}
@@ -0,0 +1,297 @@
+// Finds the container execompress appends to the stub. Same layout as
+// src/container.rs in the packer, but only the entry headers are read here:
+// each entry records where its data starts so it can be streamed from disk.
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+// Markers written after the stub executable, in the order they appear
+const MARKER_FILENAME: &[u8] = b"\n--XFILENAMEX--\n";
+const MARKER_CLEANUP: &[u8] = b"\n--CLEANUP--\n";
+const MARKER_METADATA: &[u8] = b"\n--METADATA--\n";
+const MARKER_FILE_CONTENT: &[u8] = b"\n--FILE-CONTENT--\n";
+const MARKER_EXTRA: &[u8] = b"\n--EXTRA-FILE--\n";
+
+pub struct EntryHeader {
+    pub len: u64,
+    /// Codec ID; absent in containers written before IDs were recorded
+    pub codec: Option<u8>,
+    /// Branch filter to reverse after decompression
+    pub filter: Option<String>,
+    /// Unix permission bits, recorded for files packed from archives
+    pub mode: Option<u32>,
+    /// Symlink target; symlink entries have no data
+    pub link: Option<String>,
+    /// Original size of a file in a solid block. The first file of a block
+    /// holds the stream of all of them; the files after it have no data.
+    pub solid: Option<u64>,
+}
+
+pub struct Entry {
+    /// Original filename for the main executable, relative path for extra files
+    pub name: String,
+    pub header: EntryHeader,
+    /// File offset of the compressed data
+    pub offset: u64,
+}
+
+pub struct Payload {
+    pub cleanup: bool,
+    pub main: Entry,
+    pub extras: Vec<Entry>,
+}
+
+/// Locates the payload in the stub's own image. The stub contains the marker
+/// strings too, so every candidate is tried until one chains through to the
+/// end of the file. Only the stub and the entry headers are touched.
+pub fn locate(buffer: &[u8]) -> Option<Payload> {
+    let mut search_from = 0;
+    while let Some(pos) = find(&buffer[search_from..], MARKER_FILENAME) {
+        let start = search_from + pos;
+        if let Some(payload) = parse_at(buffer, start) {
+            return Some(payload);
+        }
+        search_from = start + 1;
+    }
+    None
+}
+
+fn parse_at(buffer: &[u8], start: usize) -> Option<Payload> {
+    let mut pos = start + MARKER_FILENAME.len();
+    let name = decode_name(take_line(buffer, &mut pos)?)?;
+
+    let cleanup = buffer[pos..].starts_with(MARKER_CLEANUP);
+    if cleanup {
+        pos += MARKER_CLEANUP.len();
+    }
+
+    // Settings recorded for inspection; the stub has no use for them
+    if buffer[pos..].starts_with(MARKER_METADATA) {
+        pos += MARKER_METADATA.len();
+        let len = take_line(buffer, &mut pos)?;
+        let len = std::str::from_utf8(len).ok()?.trim().parse::<usize>().ok()?;
+        pos = pos.checked_add(1 + len)?;
+        if pos > buffer.len() {
+            return None;
+        }
+    }
+
+    if !buffer[pos..].starts_with(MARKER_FILE_CONTENT) {
+        return None;
+    }
+    pos += MARKER_FILE_CONTENT.len();
+    let main = take_entry(buffer, &mut pos, name)?;
+
+    let mut extras = vec![];
+    while pos < buffer.len() {
+        if !buffer[pos..].starts_with(MARKER_EXTRA) {
+            return None;
+        }
+        pos += MARKER_EXTRA.len();
+        let name = decode_name(take_line(buffer, &mut pos)?)?;
+        pos += 1;
+        extras.push(take_entry(buffer, &mut pos, name)?);
+    }
+
+    Some(Payload { cleanup, main, extras })
+}
+
+/// The extra files split into what is extracted as one job: a solid block
+/// with all its files, or a single file.
+pub fn jobs(extras: &[Entry]) -> Vec<&[Entry]> {
+    let mut jobs = vec![];
+    let mut start = 0;
+    for i in 1..=extras.len() {
+        let joins = i < extras.len()
+            && extras[start].header.solid.is_some()
+            && extras[i].header.solid.is_some()
+            && extras[i].header.len == 0;
+        if !joins {
+            jobs.push(&extras[start..i]);
+            start = i;
+        }
+    }
+    jobs
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Returns the bytes up to (not including) the next newline and leaves `pos` on it.
+fn take_line<'a>(buffer: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = buffer[*pos..].iter().position(|&b| b == b'\n')?;
+    let line = &buffer[*pos..*pos + len];
+    *pos += len;
+    Some(line)
+}
+
+/// Reads a `<len>[ key=value...]\n` entry header and skips over its data.
+fn take_entry(buffer: &[u8], pos: &mut usize, name: String) -> Option<Entry> {
+    let header = parse_entry_header(std::str::from_utf8(take_line(buffer, pos)?).ok()?)?;
+    *pos += 1;
+    let offset = *pos;
+    let end = pos.checked_add(usize::try_from(header.len).ok()?)?;
+    if end > buffer.len() {
+        return None;
+    }
+    *pos = end;
+    Some(Entry {
+        name,
+        header,
+        offset: offset as u64,
+    })
+}
+
+/// Parses an entry length line, "<len>[ key=value...]".
+fn parse_entry_header(line: &str) -> Option<EntryHeader> {
+    let mut fields = line.trim().split(' ');
+    let mut header = EntryHeader {
+        len: fields.next()?.parse::<u64>().ok()?,
+        codec: None,
+        filter: None,
+        mode: None,
+        link: None,
+        solid: None,
+    };
+    for field in fields {
+        let (key, value) = field.split_once('=')?;
+        match key {
+            "codec" => header.codec = Some(value.parse().ok()?),
+            "filter" => header.filter = Some(value.to_string()),
+            "mode" => header.mode = Some(u32::from_str_radix(value, 8).ok()?),
+            "link" => header.link = Some(decode_name(value.as_bytes())?),
+            "solid" => header.solid = Some(value.parse().ok()?),
+            _ => {}
+        }
+    }
+    Some(header)
+}
+
+fn decode_name(encoded: &[u8]) -> Option<String> {
+    let decoded = STANDARD.decode(encoded).ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An entry as the packer's container Writer lays it out:
+    /// `<len>[ key=value...]\n<bytes>`.
+    fn put_entry(out: &mut Vec<u8>, params: &str, data: &[u8]) {
+        out.extend_from_slice(format!("{}{}\n", data.len(), params).as_bytes());
+        out.extend_from_slice(data);
+    }
+
+    /// A stub followed by a container with a main entry and `extras`, each
+    /// given as (name, params, data).
+    fn container(extras: &[(&str, &str, &[u8])]) -> Vec<u8> {
+        // The stub holds the marker strings too, as the real stub does
+        let mut out = b"MZ stub".to_vec();
+        out.extend_from_slice(MARKER_FILENAME);
+        out.extend_from_slice(b"not a name\n");
+        out.extend_from_slice(MARKER_EXTRA);
+
+        out.extend_from_slice(MARKER_FILENAME);
+        out.extend_from_slice(STANDARD.encode("app.exe").as_bytes());
+        out.extend_from_slice(MARKER_CLEANUP);
+        let metadata = br#"{"codec":"lzma","level":"9"}"#;
+        out.extend_from_slice(MARKER_METADATA);
+        out.extend_from_slice(format!("{}\n", metadata.len()).as_bytes());
+        out.extend_from_slice(metadata);
+        out.extend_from_slice(MARKER_FILE_CONTENT);
+        put_entry(&mut out, " codec=1 filter=xz-x86", b"compressed\n--EXTRA-FILE--\nbytes");
+        for (name, params, data) in extras {
+            out.extend_from_slice(MARKER_EXTRA);
+            out.extend_from_slice(STANDARD.encode(name).as_bytes());
+            out.push(b'\n');
+            put_entry(&mut out, params, data);
+        }
+        out
+    }
+
+    fn data<'a>(buffer: &'a [u8], entry: &Entry) -> &'a [u8] {
+        let offset = entry.offset as usize;
+        &buffer[offset..offset + entry.header.len as usize]
+    }
+
+    #[test]
+    fn locates_past_markers_in_stub() {
+        let link = STANDARD.encode("../lib/v1");
+        let buffer = container(&[
+            ("assets/data.bin", " codec=2 mode=644", &[0, 1, 2, b'\n', 255]),
+            ("empty file", "", &[]),
+            ("lib/current", &format!(" link={}", link), &[]),
+        ]);
+        let payload = locate(&buffer).unwrap();
+
+        assert!(payload.cleanup);
+        assert_eq!(payload.main.name, "app.exe");
+        assert_eq!(payload.main.header.codec, Some(1));
+        assert_eq!(payload.main.header.filter.as_deref(), Some("xz-x86"));
+        assert_eq!(data(&buffer, &payload.main), b"compressed\n--EXTRA-FILE--\nbytes");
+
+        let names: Vec<&str> = payload.extras.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["assets/data.bin", "empty file", "lib/current"]);
+        assert_eq!(data(&buffer, &payload.extras[0]), [0, 1, 2, b'\n', 255]);
+        assert_eq!(payload.extras[0].header.mode, Some(0o644));
+        assert_eq!(payload.extras[1].header.len, 0);
+        assert_eq!(payload.extras[1].header.codec, None);
+        assert_eq!(payload.extras[2].header.link.as_deref(), Some("../lib/v1"));
+        assert!(payload.extras.iter().all(|e| e.header.solid.is_none()));
+    }
+
+    #[test]
+    fn solid_blocks_are_one_job() {
+        let buffer = container(&[
+            ("a", " codec=2 solid=3", b"stream"),
+            ("b", " solid=2", &[]),
+            ("c", " solid=0", &[]),
+            ("plain", " codec=2", b"data"),
+            ("empty", "", &[]),
+            ("d", " codec=3 solid=5", b"other stream"),
+            ("e", " solid=1", &[]),
+        ]);
+        let payload = locate(&buffer).unwrap();
+        let solid: Vec<Option<u64>> = payload.extras.iter().map(|e| e.header.solid).collect();
+        assert_eq!(solid, [Some(3), Some(2), Some(0), None, None, Some(5), Some(1)]);
+
+        let jobs: Vec<Vec<&str>> = jobs(&payload.extras)
+            .iter()
+            .map(|job| job.iter().map(|e| e.name.as_str()).collect())
+            .collect();
+        assert_eq!(jobs, [vec!["a", "b", "c"], vec!["plain"], vec!["empty"], vec!["d", "e"]]);
+        assert_eq!(data(&buffer, &payload.extras[0]), b"stream");
+    }
+
+    #[test]
+    fn rejects_truncated_or_trailing_bytes() {
+        let buffer = container(&[("assets/data.bin", " codec=2", b"data")]);
+        assert!(locate(&buffer).is_some());
+        assert!(locate(&buffer[..buffer.len() - 1]).is_none());
+
+        let mut trailing = buffer.clone();
+        trailing.push(0);
+        assert!(locate(&trailing).is_none());
+
+        // A length past the end of the file
+        let mut overlong = container(&[]);
+        let main_data = b"compressed\n--EXTRA-FILE--\nbytes";
+        let header = format!("{} codec=1 filter=xz-x86\n", main_data.len());
+        let at = find(&overlong, header.as_bytes()).unwrap();
+        overlong.splice(at..at + header.len(), b"99999 codec=1\n".iter().copied());
+        assert!(locate(&overlong).is_none());
+    }
+
+    #[test]
+    fn rejects_garbage_headers() {
+        for line in ["", "x", "-1", "5 codec", "5 codec=x", "5 mode=9", "5 solid=", "5 solid=-1", "5 link=!"] {
+            assert!(parse_entry_header(line).is_none(), "{:?}", line);
+        }
+        let header = parse_entry_header("5 future=1 solid=7").unwrap();
+        assert_eq!(header.len, 5);
+        assert_eq!(header.solid, Some(7));
+
+        let buffer = container(&[("bad", " codec=x", b"data")]);
+        assert!(locate(&buffer).is_none());
+    }
+}